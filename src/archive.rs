@@ -0,0 +1,533 @@
+use crate::logging::{owners, read_logs, write_logs, FileInfo, Filter, OpType};
+use crate::progress::Progress;
+use crate::trm::{get_log_file, Args};
+use crate::{copy, dedup, lock, rm_compat, store, utils};
+
+use chrono::{DateTime, Duration, Local};
+use indicatif::HumanBytes;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File},
+    io::{self, Error, ErrorKind, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use tar::{EntryType, Header, HeaderMode};
+
+/// Directory at the root of every store holding the compressed items, one archive per trash
+/// operation. Members are named by the path of the item in the trash, without the leading `/`
+pub const ARCHIVE_DIR: &str = ".trm-archive";
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Whether a path is the archive directory of a store, which is never shown as trashed
+pub fn is_archive_dir(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == ARCHIVE_DIR)
+}
+
+fn member_name(trash_path: &Path) -> PathBuf {
+    trash_path.strip_prefix("/").unwrap_or(trash_path).to_path_buf()
+}
+
+fn archive_path(store: &Path, moved_time: DateTime<Local>) -> PathBuf {
+    let name = moved_time.format("%Y%m%dT%H%M%S%.9f%z");
+    store.join(ARCHIVE_DIR).join(format!("{}.tar.zst", name))
+}
+
+/// Times of the trash entries with items older than `cutoff` still waiting to be compressed
+fn pending_entries(cutoff: DateTime<Local>) -> Vec<DateTime<Local>> {
+    let logs = read_logs(Filter::All);
+    let owner = owners(&logs);
+    logs.iter()
+        .enumerate()
+        .filter(|(index, log)| {
+            // an encrypted item would not get any smaller
+            log.operation == OpType::TRASH
+                && !log.encrypted
+                && log.moved_time < cutoff
+                && log
+                    .dst
+                    .iter()
+                    .any(|dst| owner.get(dst) == Some(index) && utils::path_exists(Path::new(dst)))
+        })
+        .map(|(_, log)| log.moved_time)
+        .collect()
+}
+
+/// Start `trm compress` in the background if compression is configured and some items are due.
+/// Its output is discarded, what it did shows up in the history
+pub fn spawn_background(args: &Args, dir_path: &Path) {
+    let Some(after) = args.compress_after else {
+        return;
+    };
+    let cutoff = Local::now() - Duration::seconds(after.as_secs() as i64);
+    if !Path::new(&get_log_file()).exists() || pending_entries(cutoff).is_empty() {
+        return;
+    }
+
+    let result = std::env::current_exe().and_then(|exe| {
+        Command::new(exe)
+            // even when `rm_compat` is set in the config file
+            .arg(rm_compat::NO_RM_FLAG)
+            .arg("--dir")
+            .arg(dir_path)
+            .arg("compress")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            // so that it is not interrupted along with the terminal it was started from
+            .process_group(0)
+            .spawn()
+    });
+    if let Err(e) = result {
+        eprintln!("Failed to start background compression: {}", e);
+    }
+}
+
+/// Compress the items of every trash operation older than `after`.
+/// The lock is taken for one operation at a time, so other runs of `trm` only wait briefly
+pub fn compress(args: &Args, dir_path: &Path, after: std::time::Duration) {
+    if !Path::new(&get_log_file()).exists() {
+        return;
+    }
+
+    let cutoff = Local::now() - Duration::seconds(after.as_secs() as i64);
+    let mut items: usize = 0;
+    let mut before_size: u64 = 0;
+    let mut after_size: u64 = 0;
+
+    for moved_time in pending_entries(cutoff) {
+        let _lock = lock::acquire();
+        match compress_entry(args, dir_path, moved_time) {
+            Ok((count, before, after)) => {
+                items += count;
+                before_size += before;
+                after_size += after;
+            }
+            Err(e) => eprintln!("Failed to compress items trashed {}: {}", moved_time.to_rfc2822(), e),
+        }
+    }
+
+    if items > 0 {
+        println!(
+            "Compressed {} item(s) from {} down to {}",
+            items,
+            HumanBytes(before_size),
+            HumanBytes(after_size)
+        );
+    }
+}
+
+/// Pack the items of one trash operation into an archive per store, then remove them.
+/// Returns the number of items compressed and the space they took up before and after
+fn compress_entry(args: &Args, dir_path: &Path, moved_time: DateTime<Local>) -> Result<(usize, u64, u64), Error> {
+    // read again under the lock, as items may have been restored since
+    let mut logs = read_logs(Filter::All);
+    let Some(index) = logs
+        .iter()
+        .position(|log| log.operation == OpType::TRASH && log.moved_time == moved_time)
+    else {
+        return Ok((0, 0, 0));
+    };
+
+    let stores = store::all_stores(dir_path);
+    let mut by_store: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    // items an interrupted run already archived, but did not remove yet
+    let mut leftovers: Vec<PathBuf> = vec![];
+    {
+        let owner = owners(&logs);
+        for dst in &logs[index].dst {
+            let path = PathBuf::from(dst);
+            if owner.get(dst) != Some(&index) || !utils::path_exists(&path) {
+                continue;
+            }
+            if logs[index].archived.contains_key(dst) {
+                leftovers.push(path);
+            } else if let Some(store) = store::store_of(&path, &stores) {
+                by_store.entry(store.clone()).or_default().push(path);
+            }
+        }
+    }
+
+    let mut created: Vec<PathBuf> = vec![];
+    let mut after_size: u64 = 0;
+    let mut archived: Vec<PathBuf> = vec![];
+
+    for (store, items) in &by_store {
+        let archive = archive_path(store, moved_time);
+        let result = write_archive(&archive, store, items).and_then(|_| verify_archive(&archive, items));
+        if let Err(e) = result {
+            for archive in created.iter().chain([&archive]) {
+                let _ = fs::remove_file(archive);
+            }
+            return Err(e);
+        }
+
+        for item in items {
+            logs[index]
+                .archived
+                .insert(item.display().to_string(), archive.display().to_string());
+        }
+        after_size += fs::metadata(&archive)?.len();
+        archived.extend(items.iter().cloned());
+        created.push(archive);
+    }
+
+    // deduplicated items share their contents, which only take up space once
+    let before_size = copy::disk_usage(&archived);
+
+    if !archived.is_empty() {
+        if let Err(e) = write_logs(&logs) {
+            for archive in &created {
+                let _ = fs::remove_file(archive);
+            }
+            return Err(e);
+        }
+    }
+
+    // only now that the log points at the archives are the items removed
+    for item in leftovers.iter().chain(&archived) {
+        let stats = copy::tree_stats(item)?;
+        let progress = Progress::new(
+            &format!("Removing {}", get_file_name!(item)),
+            stats.bytes,
+            stats.entries,
+        );
+        copy::remove_tree(item, &progress)?;
+        if let Some(store) = store::store_of(item, &stores) {
+            utils::remove_empty_parents(item, store);
+            dedup::forget(store, item);
+        }
+        if args.verbose {
+            println!("Compressed {}", item.display());
+        }
+    }
+
+    for store in by_store.keys() {
+        dedup::collect_garbage(store);
+    }
+
+    Ok((archived.len(), before_size, after_size))
+}
+
+fn write_archive(archive: &Path, store: &Path, items: &[PathBuf]) -> Result<(), Error> {
+    fs::create_dir_all(archive.parent().unwrap())?;
+    let tmp_path = archive.with_extension("tmp");
+
+    let (bytes, entries) = items
+        .iter()
+        .filter_map(|item| copy::tree_stats(item).ok())
+        .fold((0, 0), |(bytes, entries), stats| (bytes + stats.bytes, entries + stats.entries));
+    let progress = Progress::new(&format!("Compressing {}", get_file_name!(archive)), bytes, entries);
+
+    let encoder = zstd::Encoder::new(File::create(&tmp_path)?, COMPRESSION_LEVEL)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    builder.mode(HeaderMode::Complete);
+
+    let result = items.iter().try_for_each(|item| {
+        let shared = dedup::manifest(store, item)?;
+        append_item(&mut builder, item, &member_name(item), &shared, &progress)
+    });
+    let result = result.and_then(|_| {
+        let file = builder.into_inner()?.finish()?;
+        file.sync_all()
+    });
+    drop(progress);
+
+    match result {
+        Ok(_) => fs::rename(&tmp_path, archive),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Add an item to the archive under the member name `root`, keeping hard links within it and
+/// extended attributes. Deduplicated files get the attributes recorded for them instead of those
+/// of the shared inode
+pub fn append_item<W: Write>(
+    builder: &mut tar::Builder<W>,
+    item: &Path,
+    root: &Path,
+    shared: &dedup::Manifest,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let mut linked: HashMap<(u64, u64), PathBuf> = HashMap::new();
+
+    for entry in walkdir::WalkDir::new(item).follow_root_links(false) {
+        let entry = entry.map_err(Error::from)?;
+        let meta = entry.path().symlink_metadata()?;
+        let relative_path = entry.path().strip_prefix(item).unwrap();
+        let name = if relative_path.as_os_str().is_empty() {
+            root.to_path_buf()
+        } else {
+            root.join(relative_path)
+        };
+
+        append_xattrs(builder, entry.path())?;
+
+        if let Some(attrs) = relative_path.to_str().and_then(|path| shared.get(path)) {
+            let mut header = Header::new_gnu();
+            header.set_metadata_in_mode(&meta, HeaderMode::Complete);
+            header.set_mode(attrs.mode);
+            header.set_uid(attrs.uid as u64);
+            header.set_gid(attrs.gid as u64);
+            header.set_mtime(attrs.mtime.0.max(0) as u64);
+            builder.append_data(&mut header, &name, File::open(entry.path())?)?;
+        } else if meta.nlink() > 1 && !meta.is_dir() {
+            match linked.get(&(meta.dev(), meta.ino())) {
+                Some(first) => {
+                    let mut header = Header::new_gnu();
+                    header.set_metadata_in_mode(&meta, HeaderMode::Complete);
+                    header.set_entry_type(EntryType::Link);
+                    header.set_size(0);
+                    builder.append_link(&mut header, &name, first)?;
+                }
+                None => {
+                    linked.insert((meta.dev(), meta.ino()), name.clone());
+                    builder.append_path_with_name(entry.path(), &name)?;
+                }
+            }
+        } else {
+            builder.append_path_with_name(entry.path(), &name)?;
+        }
+
+        progress.inc(meta.len(), 1);
+    }
+
+    Ok(())
+}
+
+/// Extended attributes go in a pax header in front of the entry they belong to
+fn append_xattrs<W: Write>(builder: &mut tar::Builder<W>, path: &Path) -> Result<(), Error> {
+    let mut records: Vec<u8> = vec![];
+    if let Ok(names) = xattr::list(path) {
+        for name in names {
+            let Some(value) = xattr::get(path, &name)? else {
+                continue;
+            };
+            let mut record = b" SCHILY.xattr.".to_vec();
+            record.extend_from_slice(name.as_bytes());
+            record.push(b'=');
+            record.extend_from_slice(&value);
+            record.push(b'\n');
+
+            // the length at the front of a record counts its own digits
+            let mut length = record.len() + 1;
+            while record.len() + length.to_string().len() != length {
+                length = record.len() + length.to_string().len();
+            }
+            records.extend_from_slice(length.to_string().as_bytes());
+            records.extend_from_slice(&record);
+        }
+    }
+
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(records.len() as u64);
+    builder.append_data(&mut header, "PaxHeader", records.as_slice())
+}
+
+/// Read the archive back and compare it against the items it was made from,
+/// so the items are only removed once they can be restored
+fn verify_archive(archive: &Path, items: &[PathBuf]) -> Result<(), Error> {
+    let (bytes, entries) = items
+        .iter()
+        .filter_map(|item| copy::tree_stats(item).ok())
+        .fold((0, 0), |(bytes, entries), stats| (bytes + stats.bytes, entries + stats.entries));
+    let progress = Progress::new(&format!("Verifying {}", get_file_name!(archive)), bytes, entries);
+
+    let decoder = zstd::Decoder::new(File::open(archive)?)?;
+    verify_entries(decoder, |member| Path::new("/").join(member), entries, &progress)
+}
+
+/// Compare every entry of a tar stream against the file it was made from, found with `source_of`
+/// from the member name, and check that it has `entries` entries
+pub fn verify_entries<R: Read>(
+    reader: R,
+    source_of: impl Fn(&Path) -> PathBuf,
+    entries: u64,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let mut tar = tar::Archive::new(reader);
+    let mut found: u64 = 0;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let source = source_of(&entry.path()?);
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_pax_global_extensions() || entry_type.is_pax_local_extensions() {
+            continue;
+        }
+
+        if entry_type.is_file() || entry_type.is_gnu_sparse() {
+            let mut hasher = blake3::Hasher::new();
+            let size = io::copy(&mut entry, &mut hasher)?;
+            if hasher.finalize().to_hex().to_string() != copy::hash_file(&source)? {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} differs in the archive", source.display()),
+                ));
+            }
+            progress.inc(size, 1);
+        } else {
+            progress.inc(0, 1);
+        }
+        found += 1;
+    }
+
+    if found != entries {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} entries in the archive, expected {}", found, entries),
+        ));
+    }
+    Ok(())
+}
+
+/// Extract the item trashed at `trash_path` from `archive` to `target`.
+/// It is extracted under a temporary name next to `target`, then renamed into place
+pub fn extract(archive: &Path, trash_path: &Path, target: &Path) -> Result<(), Error> {
+    let staging = utils::staging_path(target, std::process::id());
+    let result = File::open(archive)
+        .and_then(zstd::Decoder::new)
+        .and_then(|decoder| unpack(decoder, &member_name(trash_path), &staging))
+        .and_then(|found| {
+            if !found {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} is not in {}", trash_path.display(), archive.display()),
+                ));
+            }
+            fs::rename(&staging, target)
+        });
+
+    if result.is_err() && utils::path_exists(&staging) {
+        let _ = utils::remove_path(&staging);
+    }
+    result
+}
+
+/// Unpack the member `member` of a tar stream to `staging`. Returns whether the member was in it
+pub fn unpack<R: Read>(reader: R, member: &Path, staging: &Path) -> Result<bool, Error> {
+    let mut tar = tar::Archive::new(reader);
+    tar.set_preserve_permissions(true);
+    tar.set_preserve_mtime(true);
+    tar.set_unpack_xattrs(true);
+    // only root can give files away, anyone else restores them as their own
+    tar.set_preserve_ownerships(unsafe { libc::geteuid() } == 0);
+
+    let name = get_file_name!(member);
+    let progress = Progress::new(&format!("Extracting {}", name), 0, 0);
+
+    // directories get their final mode and times last, as extracting their children changes them
+    let mut dirs: Vec<(PathBuf, u32, u64)> = vec![];
+    let mut found = false;
+
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let Ok(relative_path) = path.strip_prefix(member) else {
+            continue;
+        };
+        found = true;
+        let target = if relative_path.as_os_str().is_empty() {
+            staging.to_path_buf()
+        } else {
+            staging.join(relative_path)
+        };
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_hard_link() {
+            let link_name = entry
+                .link_name()?
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "hard link without a target"))?
+                .into_owned();
+            let link_path = link_name
+                .strip_prefix(member)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "hard link outside of the item"))?;
+            fs::hard_link(staging.join(link_path), &target)?;
+            progress.inc(0, 1);
+            continue;
+        }
+
+        entry.unpack(&target)?;
+        if entry_type.is_dir() {
+            let mode = entry.header().mode()?;
+            dirs.push((target.clone(), mode, entry.header().mtime()?));
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode | 0o700))?;
+        }
+        progress.inc(entry.size(), 1);
+    }
+
+    for (dir, mode, mtime) in dirs.iter().rev() {
+        fs::set_permissions(dir, fs::Permissions::from_mode(*mode))?;
+        copy::set_times(dir, (*mtime as i64, 0), (*mtime as i64, 0))?;
+    }
+
+    Ok(found)
+}
+
+/// Drop restored items from the archives they were in. An archive is deleted once
+/// nothing in it is waiting to be restored anymore
+pub fn forget_restored(restored: &[String]) -> Result<(), Error> {
+    if restored.is_empty() || !Path::new(&get_log_file()).exists() {
+        return Ok(());
+    }
+
+    let mut logs = read_logs(Filter::All);
+    let mut changed = false;
+    let mut unused: Vec<String> = vec![];
+    for dst in restored {
+        let Some(log) = logs.iter_mut().rev().find(|log| log.archived.contains_key(dst)) else {
+            continue;
+        };
+        let archive = log.archived.remove(dst).unwrap();
+        changed = true;
+        if !log.archived.values().any(|other| other == &archive) {
+            unused.push(archive);
+        }
+    }
+
+    if !changed {
+        return Ok(());
+    }
+    write_logs(&logs)?;
+
+    for archive in unused {
+        remove_archive(Path::new(&archive));
+    }
+    Ok(())
+}
+
+/// Delete the archives of a log entry which is being dropped
+pub fn remove_archives(log: &FileInfo) {
+    let archives: BTreeSet<&String> = log.archived.values().collect();
+    for archive in archives {
+        remove_archive(Path::new(archive));
+    }
+}
+
+fn remove_archive(archive: &Path) {
+    match fs::remove_file(archive) {
+        Ok(_) => {
+            if let Some(dir) = archive.parent() {
+                // only succeeds once it is empty
+                let _ = fs::remove_dir(dir);
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Failed to remove {}: {}", archive.display(), e),
+    }
+}
@@ -0,0 +1,117 @@
+use crate::conflict::OnConflict;
+use crate::trm::{get_config_file, Args};
+use crate::utils;
+
+use serde::Deserialize;
+use std::{fs, process::exit};
+
+/// Settings read from the config file. Every setting can also be given on the
+/// command line, which takes precedence
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Maximum size of the trash, e.g. `"10G"`
+    pub max_size: Option<String>,
+
+    /// Maximum percentage of the space available to the trash that it may use
+    pub max_percent: Option<f64>,
+
+    /// Record a checksum of every trashed item
+    pub hash: bool,
+
+    /// Store trashed files by their contents
+    pub dedup: bool,
+
+    /// Encrypt trashed items
+    pub encrypt: bool,
+
+    /// Also encrypt the original paths of trashed items in the log
+    pub encrypt_paths: bool,
+
+    /// File whose contents are used as the encryption key instead of a passphrase
+    pub key_file: Option<String>,
+
+    /// Paths which may not be trashed, nor anything in them or above them. A leading `~` is the home directory
+    pub protected_paths: Vec<String>,
+
+    /// Always ask once before trashing more files than `prompt_threshold`, or any directory, as with -I
+    pub interactive_once: bool,
+
+    /// Number of files above which -I asks
+    pub prompt_threshold: Option<usize>,
+
+    /// What to do when a file is restored to a path which is taken, as with --on-conflict
+    pub on_conflict: Option<OnConflict>,
+
+    /// Behave like GNU rm, see `rm_compat`. `trm --no-rm` gives the usual command line back
+    pub rm_compat: bool,
+
+    /// Age after which trashed items are compressed in the background, e.g. `"7days"`
+    pub compress_after: Option<String>,
+}
+
+/// Read the config file. A missing file means the defaults, an invalid one is an error
+pub fn load() -> Config {
+    let config_file = get_config_file();
+    let contents = match fs::read_to_string(&config_file) {
+        Ok(contents) => contents,
+        Err(_) => return Config::default(),
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid config file {}: {}", config_file.display(), e);
+            exit(1);
+        }
+    }
+}
+
+impl Config {
+    /// Fill in every setting not given on the command line
+    pub fn apply(self, args: &mut Args) {
+        if args.max_size.is_none() {
+            if let Some(max_size) = self.max_size {
+                match utils::parse_size(&max_size) {
+                    Ok(max_size) => args.max_size = Some(max_size),
+                    Err(e) => {
+                        eprintln!("Invalid max_size in {}: {}", get_config_file().display(), e);
+                        exit(1);
+                    }
+                }
+            }
+        }
+
+        if args.max_percent.is_none() {
+            args.max_percent = self.max_percent;
+        }
+
+        args.hash |= self.hash;
+        args.dedup |= self.dedup;
+        args.encrypt_paths |= self.encrypt_paths;
+        args.encrypt |= self.encrypt || args.encrypt_paths;
+        if args.key_file.is_none() {
+            args.key_file = self.key_file;
+        }
+        args.protected_paths.extend(self.protected_paths);
+        // -i asks about every file already
+        args.interactive_once |= self.interactive_once && !args.interactive;
+        if args.prompt_threshold.is_none() {
+            args.prompt_threshold = self.prompt_threshold;
+        }
+
+        if args.on_conflict.is_none() {
+            args.on_conflict = self.on_conflict;
+        }
+
+        if let Some(compress_after) = self.compress_after {
+            match humantime::parse_duration(&compress_after) {
+                Ok(compress_after) => args.compress_after = Some(compress_after),
+                Err(e) => {
+                    eprintln!("Invalid compress_after in {}: {}", get_config_file().display(), e);
+                    exit(1);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::trm::Args;
+use crate::{prompt, utils};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::path::Path;
+
+/// What to do when a file is restored to a path which is already taken
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnConflict {
+    /// Leave the file in the trash
+    Skip,
+    /// Restore the file under the first free name, e.g. `notes.txt_1`
+    #[default]
+    Rename,
+    /// Permanently delete what is there now
+    Overwrite,
+    /// Ask what to do about every conflict
+    Prompt,
+    /// Move what is there now to the trash first
+    TrashExisting,
+}
+
+/// What was decided about a single conflict
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    Skip,
+    Rename,
+    Overwrite,
+    TrashExisting,
+    /// Left to the user, only in a dry run
+    Ask,
+}
+
+/// Decide what to do about `existing` being in the way of restoring `file`
+pub fn resolve(args: &Args, file: &Path, existing: &Path) -> Resolution {
+    match args.on_conflict.unwrap_or_default() {
+        OnConflict::Skip => Resolution::Skip,
+        OnConflict::Rename => Resolution::Rename,
+        OnConflict::Overwrite => Resolution::Overwrite,
+        OnConflict::TrashExisting => Resolution::TrashExisting,
+        OnConflict::Prompt if args.dry_run => Resolution::Ask,
+        OnConflict::Prompt => ask(args, file, existing),
+    }
+}
+
+/// Anything but a known answer skips the file, so that nothing is lost by mistake
+fn ask(args: &Args, file: &Path, existing: &Path) -> Resolution {
+    let prompt = format!(
+        "{} is in the way of restoring {}. [s]kip, [r]ename, [o]verwrite or [t]rash it? [s]",
+        existing.display(),
+        file.display()
+    );
+    match prompt::answer(&prompt, utils::list_from_stdin(args)).to_lowercase().as_str() {
+        "r" | "rename" => Resolution::Rename,
+        "o" | "overwrite" => Resolution::Overwrite,
+        "t" | "trash" => Resolution::TrashExisting,
+        _ => Resolution::Skip,
+    }
+}
+
+/// How a conflict is reported, e.g. `/home/me/notes.txt already exists, restoring as /home/me/notes.txt_1`
+pub fn report(resolution: Resolution, existing: &Path, target: &Path) -> String {
+    let action = match resolution {
+        Resolution::Skip => "leaving the file in the trash".to_string(),
+        Resolution::Rename => format!("restoring as {}", target.display()),
+        Resolution::Overwrite => "deleting it".to_string(),
+        Resolution::TrashExisting => "trashing it".to_string(),
+        Resolution::Ask => "would ask what to do".to_string(),
+    };
+    format!("{} already exists, {}", existing.display(), action)
+}
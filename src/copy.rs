@@ -0,0 +1,455 @@
+use crate::progress::Progress;
+
+use rayon::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    fs::{self, File, Metadata, OpenOptions},
+    io::{self, Error, ErrorKind, Read, Seek, SeekFrom},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{lchown, symlink, MetadataExt, PermissionsExt},
+        },
+    },
+    path::{Path, PathBuf},
+};
+
+/// Copy a file, symlink or directory tree, keeping it indistinguishable from the original.
+///
+/// Preserves mode, ownership (when permitted), access and modification times, extended
+/// attributes (which includes ACLs), holes in sparse files and hard links within the tree.
+/// Symlinks are copied as links and special files are recreated
+pub fn copy_tree(src: &Path, dst: &Path, progress: &Progress) -> Result<(), Error> {
+    // (device, inode) of files with more than one link, to the first copy made of them
+    let mut linked: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    // directory metadata is applied last, as creating the children changes their times
+    let mut dirs: Vec<(PathBuf, Metadata, PathBuf)> = vec![];
+    let mut files: Vec<(PathBuf, Metadata, PathBuf)> = vec![];
+    let mut hard_links: Vec<(PathBuf, PathBuf, u64)> = vec![];
+
+    // the directories are created while walking, so that the files can then be copied in parallel
+    for entry in walkdir::WalkDir::new(src).follow_root_links(false) {
+        let entry = entry.map_err(Error::from)?;
+        let target = target_path(entry.path(), src, dst);
+        let meta = entry.path().symlink_metadata()?;
+
+        if meta.is_dir() {
+            fs::create_dir(&target)?;
+            progress.inc(meta.len(), 1);
+            dirs.push((entry.path().to_path_buf(), meta, target));
+            continue;
+        }
+
+        if meta.nlink() > 1 {
+            if let Some(first_copy) = linked.get(&(meta.dev(), meta.ino())) {
+                hard_links.push((first_copy.clone(), target, meta.len()));
+                continue;
+            }
+            linked.insert((meta.dev(), meta.ino()), target.clone());
+        }
+
+        files.push((entry.path().to_path_buf(), meta, target));
+    }
+
+    files.par_iter().try_for_each(|(file, meta, target)| {
+        let file_type = meta.file_type();
+        if file_type.is_symlink() {
+            symlink(fs::read_link(file)?, target)?;
+            progress.inc(meta.len(), 0);
+        } else if file_type.is_file() {
+            copy_file_contents(file, target, meta, progress)?;
+        } else {
+            make_special_file(target, meta)?;
+        }
+        copy_metadata(file, target, meta)?;
+        progress.inc(0, 1);
+        Ok::<(), Error>(())
+    })?;
+
+    // the files being linked to exist now
+    for (first_copy, target, len) in &hard_links {
+        fs::hard_link(first_copy, target)?;
+        progress.inc(*len, 1);
+    }
+
+    for (dir, meta, target) in dirs.iter().rev() {
+        copy_metadata(dir, target, meta)?;
+    }
+
+    Ok(())
+}
+
+/// Where an entry under `src` goes when `src` is copied to `dst`
+fn target_path(path: &Path, src: &Path, dst: &Path) -> PathBuf {
+    let relative_path = path.strip_prefix(src).unwrap();
+    if relative_path.as_os_str().is_empty() {
+        dst.to_path_buf()
+    } else {
+        dst.join(relative_path)
+    }
+}
+
+/// Copy the contents of a regular file, only writing the data regions so that holes stay holes
+pub fn copy_file_contents(src: &Path, dst: &Path, meta: &Metadata, progress: &Progress) -> Result<(), Error> {
+    let mut src_file = File::open(src)?;
+    let mut dst_file = OpenOptions::new().write(true).create_new(true).open(dst)?;
+
+    let len = meta.len() as i64;
+    let fd = src_file.as_raw_fd();
+    let mut offset: i64 = 0;
+
+    while offset < len {
+        let data_start = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+        if data_start < 0 {
+            let e = Error::last_os_error();
+            match e.raw_os_error() {
+                // only a hole is left until the end of the file
+                Some(libc::ENXIO) => break,
+                // the filesystem cannot report holes, so copy everything
+                Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) if offset == 0 => {
+                    src_file.seek(SeekFrom::Start(0))?;
+                    copy_range(&mut src_file, &mut dst_file, meta.len(), progress)?;
+                    return Ok(());
+                }
+                _ => return Err(e),
+            }
+        }
+
+        let data_end = unsafe { libc::lseek(fd, data_start, libc::SEEK_HOLE) };
+        if data_end < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        src_file.seek(SeekFrom::Start(data_start as u64))?;
+        dst_file.seek(SeekFrom::Start(data_start as u64))?;
+        let length = (data_end - data_start) as u64;
+        if copy_range(&mut src_file, &mut dst_file, length, progress)? != length {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!("{} changed while copying", src.display())));
+        }
+
+        // holes count as done without copying anything
+        progress.inc((data_start - offset) as u64, 0);
+        offset = data_end;
+    }
+
+    // a trailing hole is recreated by extending the file
+    dst_file.set_len(len as u64)?;
+    progress.inc((len - offset.min(len)) as u64, 0);
+    Ok(())
+}
+
+/// How much is copied or hashed between progress updates
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Copy `length` bytes from the current positions, in chunks so that progress keeps moving on big files
+fn copy_range(src_file: &mut File, dst_file: &mut File, length: u64, progress: &Progress) -> Result<u64, Error> {
+    let mut copied: u64 = 0;
+    while copied < length {
+        let chunk = io::copy(&mut src_file.take(CHUNK_SIZE.min(length - copied)), dst_file)?;
+        if chunk == 0 {
+            break;
+        }
+        copied += chunk;
+        progress.inc(chunk, 0);
+    }
+    Ok(copied)
+}
+
+fn c_path(path: &Path) -> Result<CString, Error> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+/// Recreate a fifo, socket or device node
+fn make_special_file(dst: &Path, meta: &Metadata) -> Result<(), Error> {
+    let path = c_path(dst)?;
+    if unsafe { libc::mknod(path.as_ptr(), meta.mode() as libc::mode_t, meta.rdev() as libc::dev_t) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Errors which mean the destination does not support, or we may not set, a piece of metadata
+pub fn is_not_permitted(e: &Error) -> bool {
+    e.kind() == ErrorKind::Unsupported
+        || matches!(e.raw_os_error(), Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::EOPNOTSUPP))
+}
+
+fn copy_metadata(src: &Path, dst: &Path, meta: &Metadata) -> Result<(), Error> {
+    // extended attributes also carry ACLs, so they go before the mode
+    if let Ok(names) = xattr::list(src) {
+        for name in names {
+            if let Some(value) = xattr::get(src, &name)? {
+                match xattr::set(dst, &name, &value) {
+                    Err(e) if is_not_permitted(&e) => {}
+                    result => result?,
+                }
+            }
+        }
+    }
+
+    // changing the owner clears setuid and setgid bits, so it goes before the mode
+    match lchown(dst, Some(meta.uid()), Some(meta.gid())) {
+        Err(e) if is_not_permitted(&e) => {}
+        result => result?,
+    }
+
+    if !meta.file_type().is_symlink() {
+        fs::set_permissions(dst, fs::Permissions::from_mode(meta.mode()))?;
+    }
+
+    set_times(dst, (meta.atime(), meta.atime_nsec()), (meta.mtime(), meta.mtime_nsec()))
+}
+
+/// Set the access and modification times of a path, given as (seconds, nanoseconds).
+/// Symlinks themselves are changed, not what they point to
+pub fn set_times(path: &Path, atime: (i64, i64), mtime: (i64, i64)) -> Result<(), Error> {
+    let times = [
+        libc::timespec {
+            tv_sec: atime.0 as libc::time_t,
+            tv_nsec: atime.1 as _,
+        },
+        libc::timespec {
+            tv_sec: mtime.0 as libc::time_t,
+            tv_nsec: mtime.1 as _,
+        },
+    ];
+    let path = c_path(path)?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Sizes of a file or directory tree
+pub struct TreeStats {
+    /// Space taken up on disk. Holes in sparse files are not counted and
+    /// files with several hard links in the tree are counted once
+    pub disk_usage: u64,
+
+    /// Sum of the apparent sizes of every entry
+    pub bytes: u64,
+
+    /// Number of entries, including the root
+    pub entries: u64,
+}
+
+pub fn tree_stats(path: &Path) -> Result<TreeStats, Error> {
+    let mut seen: HashMap<(u64, u64), ()> = HashMap::new();
+    let mut stats = TreeStats {
+        disk_usage: 0,
+        bytes: 0,
+        entries: 0,
+    };
+
+    for entry in walkdir::WalkDir::new(path).follow_root_links(false) {
+        let meta = entry.map_err(Error::from)?.path().symlink_metadata()?;
+        stats.entries += 1;
+        stats.bytes += meta.len();
+        if meta.nlink() > 1 && !meta.is_dir() && seen.insert((meta.dev(), meta.ino()), ()).is_some() {
+            continue;
+        }
+        stats.disk_usage += meta.blocks() * 512;
+    }
+
+    Ok(stats)
+}
+
+/// Space taken up on disk by several trees together. A file hard linked from more than one of
+/// them, like a deduplicated one, is counted once. Entries which cannot be read are left out
+pub fn disk_usage(paths: &[PathBuf]) -> u64 {
+    let mut seen: HashSet<(u64, u64)> = HashSet::new();
+    paths
+        .iter()
+        .flat_map(|path| walkdir::WalkDir::new(path).follow_root_links(false))
+        .filter_map(|entry| entry.ok()?.path().symlink_metadata().ok())
+        .filter(|meta| meta.nlink() == 1 || meta.is_dir() || seen.insert((meta.dev(), meta.ino())))
+        .map(|meta| meta.blocks() * 512)
+        .sum()
+}
+
+/// Remove a file, symlink or directory tree, reporting progress.
+/// Files are removed in parallel, then directories from the deepest level up
+pub fn remove_tree(path: &Path, progress: &Progress) -> Result<(), Error> {
+    let mut files: Vec<(PathBuf, u64)> = vec![];
+    let mut dirs_by_depth: Vec<Vec<(PathBuf, u64)>> = vec![];
+
+    for entry in walkdir::WalkDir::new(path).follow_root_links(false) {
+        let entry = entry.map_err(Error::from)?;
+        if entry.file_type().is_dir() {
+            if dirs_by_depth.len() <= entry.depth() {
+                dirs_by_depth.resize(entry.depth() + 1, vec![]);
+            }
+            let len = entry.metadata().map(|meta| meta.len()).unwrap_or(0);
+            dirs_by_depth[entry.depth()].push((entry.into_path(), len));
+        } else {
+            let len = entry.metadata().map(|meta| meta.len()).unwrap_or(0);
+            files.push((entry.into_path(), len));
+        }
+    }
+
+    files.par_iter().try_for_each(|(file, len)| {
+        fs::remove_file(file)?;
+        progress.inc(*len, 1);
+        Ok::<(), Error>(())
+    })?;
+
+    for dirs in dirs_by_depth.iter().rev() {
+        dirs.par_iter().try_for_each(|(dir, len)| {
+            fs::remove_dir(dir)?;
+            progress.inc(*len, 1);
+            Ok::<(), Error>(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Space available to unprivileged users on the filesystem holding `path`
+pub fn available_space(path: &Path) -> Result<u64, Error> {
+    let c_path = c_path(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// BLAKE3 checksum of a file's contents, as hex
+pub fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// BLAKE3 checksum of a whole tree, as hex. Covers the names, types, link targets and
+/// file contents of every entry, so any change to the tree changes the checksum
+pub fn hash_tree(path: &Path) -> Result<String, Error> {
+    let meta = path.symlink_metadata()?;
+    if meta.is_file() {
+        return hash_file(path);
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    for entry in walkdir::WalkDir::new(path).follow_root_links(false).sort_by_file_name() {
+        let entry = entry.map_err(Error::from)?;
+        let relative_path = entry.path().strip_prefix(path).unwrap();
+        hasher.update(relative_path.as_os_str().as_bytes());
+        hasher.update(&[0]);
+
+        let file_type = entry.file_type();
+        if file_type.is_symlink() {
+            hasher.update(b"l");
+            hasher.update(fs::read_link(entry.path())?.as_os_str().as_bytes());
+        } else if file_type.is_dir() {
+            hasher.update(b"d");
+        } else if file_type.is_file() {
+            hasher.update(b"f");
+            hasher.update(hash_file(entry.path())?.as_bytes());
+        } else {
+            hasher.update(b"o");
+        }
+        hasher.update(&[0]);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Same as `hash_file`, reporting the bytes read as progress
+fn hash_file_with_progress(path: &Path, progress: &Progress) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    loop {
+        let chunk = io::copy(&mut (&mut file).take(CHUNK_SIZE), &mut hasher)?;
+        if chunk == 0 {
+            break;
+        }
+        progress.inc(chunk, 0);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Check that `dst` is a faithful copy of `src`: the same entries, with the same types,
+/// sizes, link targets and file checksums
+pub fn verify_tree(src: &Path, dst: &Path, progress: &Progress) -> Result<(), Error> {
+    let mismatch = |path: &Path, what: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Copy of {} does not match the original: {}", path.display(), what),
+        )
+    };
+
+    let mut src_entries: u64 = 0;
+    let mut files: Vec<(PathBuf, PathBuf)> = vec![];
+
+    for entry in walkdir::WalkDir::new(src).follow_root_links(false) {
+        let entry = entry.map_err(Error::from)?;
+        let target = target_path(entry.path(), src, dst);
+        src_entries += 1;
+
+        let src_meta = entry.path().symlink_metadata()?;
+        let dst_meta = match target.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(_) => return Err(mismatch(entry.path(), "missing")),
+        };
+
+        if src_meta.file_type() != dst_meta.file_type() {
+            return Err(mismatch(entry.path(), "different file type"));
+        }
+
+        if src_meta.file_type().is_symlink() {
+            if fs::read_link(entry.path())? != fs::read_link(&target)? {
+                return Err(mismatch(entry.path(), "different link target"));
+            }
+        } else if src_meta.is_file() {
+            if src_meta.len() != dst_meta.len() {
+                return Err(mismatch(entry.path(), "different size"));
+            }
+            files.push((entry.into_path(), target));
+            continue;
+        }
+        progress.inc(src_meta.len(), 1);
+    }
+
+    // checksums are the slow part, so they are computed in parallel
+    files.par_iter().try_for_each(|(file, target)| {
+        if hash_file_with_progress(file, progress)? != hash_file(target)? {
+            return Err(mismatch(file, "different checksum"));
+        }
+        progress.inc(0, 1);
+        Ok(())
+    })?;
+
+    let dst_entries = walkdir::WalkDir::new(dst).follow_root_links(false).into_iter().count() as u64;
+    if dst_entries != src_entries {
+        return Err(mismatch(src, "different number of entries"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hard_links_across_trees_count_once() {
+        let dir = std::env::temp_dir().join(format!("trm-test-{}-disk-usage", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let trees: Vec<PathBuf> = ["a", "b", "c"].iter().map(|name| dir.join(name)).collect();
+        for tree in &trees {
+            fs::create_dir_all(tree).unwrap();
+        }
+        fs::write(trees[0].join("blob"), vec![1u8; 64 * 1024]).unwrap();
+        fs::hard_link(trees[0].join("blob"), trees[1].join("blob")).unwrap();
+        fs::hard_link(trees[0].join("blob"), trees[2].join("blob")).unwrap();
+
+        // the blob is counted with the first tree, the others only add their directory
+        let dir_usage = |tree: &PathBuf| tree.symlink_metadata().unwrap().blocks() * 512;
+        let expected = tree_stats(&trees[0]).unwrap().disk_usage + dir_usage(&trees[1]) + dir_usage(&trees[2]);
+        assert_eq!(disk_usage(&trees), expected);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,615 @@
+use crate::logging::{read_logs, FileInfo, Filter, OpType};
+use crate::progress::Progress;
+use crate::trm::{get_key_file, get_log_file, Args};
+use crate::{archive, copy, dedup, utils};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{
+        generic_array::GenericArray,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, KeyInit,
+    },
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+use tar::HeaderMode;
+
+/// Directory at the root of every store holding the items whose original paths are encrypted.
+/// Items are named by a random id, so nothing in the trash tells where they came from
+pub const VAULT_DIR: &str = ".trm-vault";
+
+/// Start of every encrypted item. It is followed by the salt of the key, the nonce of the
+/// stream and the tar stream of the item, encrypted in chunks
+const MAGIC: &[u8] = b"TRM-ENC1";
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const STREAM_NONCE_SIZE: usize = 19;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// Name of the item inside its encrypted tar stream
+const ITEM_MEMBER: &str = "item";
+
+/// Prefix of an encrypted path in the log
+const SEALED_PREFIX: &str = "enc:";
+
+/// Shown instead of an encrypted path when there is no key for it
+pub const HIDDEN_PATH: &str = "(encrypted path)";
+
+/// Encrypted with the key and kept in the key file, so a wrong passphrase is caught
+/// before anything is trashed with it
+const CHECK_TEXT: &[u8] = b"trm key check";
+
+const KEY_FILE_CONTEXT: &str = "trm 2025 key file";
+
+enum Secret {
+    Passphrase(String),
+    KeyFile(Vec<u8>),
+}
+
+/// What the encryption keys are derived from. Every item records the salt its key was
+/// derived with, so items stay readable if the salt for new items changes
+pub struct Key {
+    secret: Secret,
+    salt: [u8; SALT_SIZE],
+    ciphers: RefCell<HashMap<[u8; SALT_SIZE], XChaCha20Poly1305>>,
+}
+
+/// Contents of the key file next to the log
+#[derive(Serialize, Deserialize)]
+struct KeyCheck {
+    salt: String,
+    check: String,
+}
+
+impl Key {
+    fn new(secret: Secret, salt: [u8; SALT_SIZE]) -> Key {
+        Key {
+            secret,
+            salt,
+            ciphers: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn cipher(&self, salt: &[u8; SALT_SIZE]) -> Result<XChaCha20Poly1305, Error> {
+        if let Some(cipher) = self.ciphers.borrow().get(salt) {
+            return Ok(cipher.clone());
+        }
+
+        let mut key = [0u8; 32];
+        match &self.secret {
+            Secret::Passphrase(passphrase) => Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                .map_err(|e| Error::other(e.to_string()))?,
+            Secret::KeyFile(contents) => {
+                let mut hasher = blake3::Hasher::new_derive_key(KEY_FILE_CONTEXT);
+                hasher.update(salt).update(contents);
+                key = *hasher.finalize().as_bytes();
+            }
+        }
+
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        self.ciphers.borrow_mut().insert(*salt, cipher.clone());
+        Ok(cipher)
+    }
+
+    fn seal(&self, plain: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        random_bytes(&mut nonce)?;
+        let sealed = self
+            .cipher(&self.salt)?
+            .encrypt(XNonce::from_slice(&nonce), plain)
+            .map_err(|_| Error::other("encryption failed"))?;
+        Ok([&self.salt[..], &nonce, &sealed].concat())
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < SALT_SIZE + NONCE_SIZE {
+            return Err(wrong_key());
+        }
+        let (salt, sealed) = sealed.split_at(SALT_SIZE);
+        let (nonce, sealed) = sealed.split_at(NONCE_SIZE);
+        self.cipher(salt.try_into().unwrap())?
+            .decrypt(XNonce::from_slice(nonce), sealed)
+            .map_err(|_| wrong_key())
+    }
+}
+
+fn wrong_key() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "cannot decrypt, the passphrase or key file is wrong or the data is damaged",
+    )
+}
+
+fn random_bytes(buf: &mut [u8]) -> Result<(), Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = unsafe { libc::getrandom(buf[filled..].as_mut_ptr().cast(), buf.len() - filled, 0) };
+        if read < 0 {
+            let e = Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        filled += read as usize;
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Read a line from the terminal without echoing it
+fn read_hidden(prompt: &str) -> Result<String, Error> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    write!(tty, "{}", prompt)?;
+    tty.flush()?;
+
+    let fd = tty.as_raw_fd();
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut term) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let original = term;
+    term.c_lflag &= !libc::ECHO;
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+
+    let mut line = String::new();
+    let result = BufReader::new(&tty).read_line(&mut line);
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    writeln!(tty)?;
+    result?;
+
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// The passphrase from `TRM_PASSPHRASE`, or else asked for on the terminal.
+/// A new passphrase is asked for twice
+fn passphrase(new: bool) -> Result<String, String> {
+    if let Ok(passphrase) = std::env::var("TRM_PASSPHRASE") {
+        if !passphrase.is_empty() {
+            return Ok(passphrase);
+        }
+    }
+
+    let no_terminal = |e: Error| format!("No passphrase given, set TRM_PASSPHRASE or use a key file: {}", e);
+    let passphrase = read_hidden("Passphrase for the trash: ").map_err(no_terminal)?;
+    if passphrase.is_empty() {
+        return Err("Empty passphrase".to_string());
+    }
+    if new && read_hidden("Repeat passphrase: ").map_err(no_terminal)? != passphrase {
+        return Err("Passphrases do not match".to_string());
+    }
+    Ok(passphrase)
+}
+
+/// Read the check of the key used before, if there is one yet.
+/// Anyone can create it in /tmp, so it is only trusted if it is a file of the user
+fn read_key_check() -> Result<Option<KeyCheck>, String> {
+    let path = get_key_file();
+    let mut file = match OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to open key file {}: {}", path, e)),
+    };
+    let trusted = file
+        .metadata()
+        .is_ok_and(|meta| meta.is_file() && utils::owned_by_user(&meta));
+    if !trusted {
+        return Err(format!("Key file {} is not a file owned by you", path));
+    }
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .map_err(|e| format!("Failed to read key file {}: {}", path, e))?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|_| format!("Invalid key file {}", path))
+}
+
+/// Get the key from the key file or the passphrase, and check it against the one used before.
+/// The first time a key is used, it becomes the one to check against
+pub fn try_load_key(args: &Args) -> Result<Key, String> {
+    let key_check = read_key_check()?;
+
+    let secret = match &args.key_file {
+        Some(path) => {
+            Secret::KeyFile(fs::read(path).map_err(|e| format!("Failed to read key file {}: {}", path, e))?)
+        }
+        None => Secret::Passphrase(passphrase(key_check.is_none())?),
+    };
+
+    if let Some(key_check) = key_check {
+        let invalid = || format!("Invalid key file {}", get_key_file());
+        let salt = from_hex(&key_check.salt)
+            .and_then(|salt| salt.try_into().ok())
+            .ok_or_else(invalid)?;
+        let check = from_hex(&key_check.check).ok_or_else(invalid)?;
+
+        let key = Key::new(secret, salt);
+        return match key.open(&check) {
+            Ok(text) if text == CHECK_TEXT => Ok(key),
+            _ => Err("Wrong passphrase or key file".to_string()),
+        };
+    }
+
+    let mut salt = [0u8; SALT_SIZE];
+    random_bytes(&mut salt).map_err(|e| format!("Failed to generate a salt: {}", e))?;
+    let key = Key::new(secret, salt);
+
+    let write_check = || -> Result<(), Error> {
+        let key_check = KeyCheck {
+            salt: to_hex(&salt),
+            check: to_hex(&key.seal(CHECK_TEXT)?),
+        };
+        // never follow or reuse a file someone else put there
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(get_key_file())?;
+        file.write_all(serde_json::to_string(&key_check)?.as_bytes())?;
+        file.sync_data()
+    };
+    write_check().map_err(|e| format!("Failed to write key file {}: {}", get_key_file(), e))?;
+
+    Ok(key)
+}
+
+pub fn load_key(args: &Args) -> Key {
+    match try_load_key(args) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The key, only when an item whose original path is encrypted is still in the trash.
+/// Without a key those items stay hidden
+pub fn vault_key(args: &Args) -> Option<Key> {
+    if !Path::new(&get_log_file()).exists() {
+        return None;
+    }
+    let in_vault = read_logs(Filter::All)
+        .iter()
+        .filter(|log| log.operation == OpType::TRASH)
+        .any(|log| {
+            log.src
+                .iter()
+                .zip(&log.dst)
+                .any(|(src, dst)| is_sealed(src) && utils::path_exists(Path::new(dst)))
+        });
+    if !in_vault {
+        return None;
+    }
+    ask_key(args)
+}
+
+/// The key, only when some of the log entries about to be shown have encrypted paths
+pub fn history_key(args: &Args, logs: &[FileInfo]) -> Option<Key> {
+    let has_sealed = logs
+        .iter()
+        .any(|log| log.src.iter().chain(&log.dst).any(|path| is_sealed(path)));
+    if !has_sealed {
+        return None;
+    }
+    ask_key(args)
+}
+
+fn ask_key(args: &Args) -> Option<Key> {
+    match try_load_key(args) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            eprintln!("Encrypted paths are hidden: {}", e);
+            None
+        }
+    }
+}
+
+/// Whether a path is the vault directory of a store, which is never shown as trashed
+pub fn is_vault_dir(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == VAULT_DIR)
+}
+
+/// Whether a path in the trash is an item with an encrypted original path
+pub fn in_vault(trash_path: &Path) -> bool {
+    trash_path.parent().is_some_and(is_vault_dir)
+}
+
+/// A new path for an item in the vault of `store`
+pub fn vault_path(store: &Path) -> Result<PathBuf, Error> {
+    let mut id = [0u8; 16];
+    random_bytes(&mut id)?;
+    Ok(store.join(VAULT_DIR).join(to_hex(&id)))
+}
+
+pub fn is_sealed(path: &str) -> bool {
+    path.starts_with(SEALED_PREFIX)
+}
+
+/// Encrypt a path for the log
+pub fn seal_path(key: &Key, path: &str) -> Result<String, Error> {
+    Ok(format!("{}{}", SEALED_PREFIX, to_hex(&key.seal(path.as_bytes())?)))
+}
+
+fn open_path(key: &Key, path: &str) -> Result<String, Error> {
+    let sealed = path
+        .strip_prefix(SEALED_PREFIX)
+        .and_then(from_hex)
+        .ok_or_else(wrong_key)?;
+    String::from_utf8(key.open(&sealed)?).map_err(|_| wrong_key())
+}
+
+/// A path from the log as it should be shown, decrypted if there is a key for it
+pub fn reveal_path(path: &str, key: Option<&Key>) -> String {
+    if !is_sealed(path) {
+        return path.to_string();
+    }
+    key.and_then(|key| open_path(key, path).ok())
+        .unwrap_or_else(|| HIDDEN_PATH.to_string())
+}
+
+/// Items in the vaults with their original path, from the oldest to the latest trashed
+pub fn vault_items(key: &Key) -> Vec<(PathBuf, PathBuf)> {
+    let mut items: Vec<(PathBuf, PathBuf)> = vec![];
+    for log in read_logs(Filter::All) {
+        if log.operation != OpType::TRASH {
+            continue;
+        }
+        for (src, dst) in log.src.iter().zip(log.dst) {
+            let dst = PathBuf::from(dst);
+            if !is_sealed(src) || !utils::path_exists(&dst) {
+                continue;
+            }
+            match open_path(key, src) {
+                Ok(original) => items.push((dst, PathBuf::from(original))),
+                Err(e) => eprintln!("Failed to decrypt the original path of {}: {}", dst.display(), e),
+            }
+        }
+    }
+    items
+}
+
+/// Encrypts what is written to it in chunks, each with its own tag, so that it can be
+/// decrypted as a stream and a truncated file is detected
+struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    fn finish(mut self) -> Result<W, Error> {
+        let encryptor = self.encryptor.take().unwrap();
+        let chunk = encryptor
+            .encrypt_last(self.buffer.as_slice())
+            .map_err(|_| Error::other("encryption failed"))?;
+        self.inner.write_all(&chunk)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        // the last chunk is written by `finish`, so a full buffer is only written once more follows
+        while self.buffer.len() > CHUNK_SIZE {
+            let chunk = self
+                .encryptor
+                .as_mut()
+                .unwrap()
+                .encrypt_next(&self.buffer[..CHUNK_SIZE])
+                .map_err(|_| Error::other("encryption failed"))?;
+            self.inner.write_all(&chunk)?;
+            self.buffer.drain(..CHUNK_SIZE);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    pending: Vec<u8>,
+    plain: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Decrypt the next chunk. One byte past a full chunk is read to know whether it is the last
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let Some(decryptor) = self.decryptor.as_mut() else {
+            self.plain.clear();
+            return Ok(());
+        };
+
+        let wanted = CHUNK_SIZE + TAG_SIZE + 1;
+        while self.pending.len() < wanted {
+            let start = self.pending.len();
+            self.pending.resize(wanted, 0);
+            let read = match self.inner.read(&mut self.pending[start..]) {
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {
+                    self.pending.truncate(start);
+                    continue;
+                }
+                Err(e) => {
+                    self.pending.truncate(start);
+                    return Err(e);
+                }
+            };
+            self.pending.truncate(start + read);
+            if read == 0 {
+                break;
+            }
+        }
+
+        self.plain = if self.pending.len() == wanted {
+            let plain = decryptor
+                .decrypt_next(&self.pending[..CHUNK_SIZE + TAG_SIZE])
+                .map_err(|_| wrong_key())?;
+            self.pending.drain(..CHUNK_SIZE + TAG_SIZE);
+            plain
+        } else {
+            let decryptor = self.decryptor.take().unwrap();
+            decryptor.decrypt_last(self.pending.as_slice()).map_err(|_| wrong_key())?
+        };
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.plain.len() {
+            self.next_chunk()?;
+        }
+        let read = buf.len().min(self.plain.len() - self.position);
+        buf[..read].copy_from_slice(&self.plain[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Read the header of an encrypted item and return a reader of its tar stream
+fn open_item(key: &Key, item: &Path) -> Result<DecryptReader<BufReader<File>>, Error> {
+    let mut reader = BufReader::new(File::open(item)?);
+    let mut header = [0u8; MAGIC.len() + SALT_SIZE + STREAM_NONCE_SIZE];
+    reader.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not an encrypted item", item.display()),
+        ));
+    }
+
+    let salt: [u8; SALT_SIZE] = header[MAGIC.len()..MAGIC.len() + SALT_SIZE].try_into().unwrap();
+    let nonce = GenericArray::from_slice(&header[MAGIC.len() + SALT_SIZE..]);
+    Ok(DecryptReader {
+        inner: reader,
+        decryptor: Some(DecryptorBE32::from_aead(key.cipher(&salt)?, nonce)),
+        pending: vec![],
+        plain: vec![],
+        position: 0,
+    })
+}
+
+fn write_item(key: &Key, src: &Path, staging: &Path, progress: &Progress) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(staging)?;
+    let mut nonce = [0u8; STREAM_NONCE_SIZE];
+    random_bytes(&mut nonce)?;
+    file.write_all(MAGIC)?;
+    file.write_all(&key.salt)?;
+    file.write_all(&nonce)?;
+
+    let writer = EncryptWriter {
+        inner: BufWriter::new(file),
+        encryptor: Some(EncryptorBE32::from_aead(
+            key.cipher(&key.salt)?,
+            GenericArray::from_slice(&nonce),
+        )),
+        buffer: Vec::with_capacity(CHUNK_SIZE),
+    };
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    builder.mode(HeaderMode::Complete);
+    archive::append_item(&mut builder, src, Path::new(ITEM_MEMBER), &dedup::Manifest::new(), progress)?;
+
+    let file = builder.into_inner()?.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()
+}
+
+/// Encrypt an item into a single file at `dst`, then remove the original.
+/// As with a copy across filesystems, the original is only removed once the encrypted file
+/// has been read back and compared against it
+pub fn encrypt_item(key: &Key, src: &Path, dst: &Path) -> Result<(), Error> {
+    let stats = copy::tree_stats(src)?;
+    utils::ensure_space(dst.parent().unwrap_or(Path::new("/")), stats.disk_usage)?;
+
+    let name = get_file_name!(src);
+    let staging = utils::staging_path(dst, std::process::id());
+    let result = {
+        let progress = Progress::new(&format!("Encrypting {}", name), stats.bytes, stats.entries);
+        write_item(key, src, &staging, &progress)
+    }
+    .and_then(|_| {
+        let progress = Progress::new(&format!("Verifying {}", name), stats.bytes, stats.entries);
+        let source_of = |member: &Path| match member.strip_prefix(ITEM_MEMBER) {
+            Ok(relative_path) if !relative_path.as_os_str().is_empty() => src.join(relative_path),
+            _ => src.to_path_buf(),
+        };
+        archive::verify_entries(open_item(key, &staging)?, source_of, stats.entries, &progress)
+    })
+    .and_then(|_| fs::rename(&staging, dst));
+
+    if let Err(e) = result {
+        if utils::path_exists(&staging) {
+            let _ = fs::remove_file(&staging);
+        }
+        return Err(e);
+    }
+
+    copy::remove_tree(
+        src,
+        &Progress::new(&format!("Removing {}", name), stats.bytes, stats.entries),
+    )
+}
+
+/// Decrypt an item to `target`, then remove the encrypted file.
+/// It is decrypted under a temporary name next to `target`, then renamed into place
+pub fn decrypt_item(key: &Key, item: &Path, target: &Path) -> Result<(), Error> {
+    let staging = utils::staging_path(target, std::process::id());
+    let result = open_item(key, item)
+        .and_then(|reader| archive::unpack(reader, Path::new(ITEM_MEMBER), &staging))
+        .and_then(|found| {
+            if !found {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} holds no item", item.display()),
+                ));
+            }
+            fs::rename(&staging, target)
+        });
+
+    if let Err(e) = result {
+        if utils::path_exists(&staging) {
+            let _ = utils::remove_path(&staging);
+        }
+        return Err(e);
+    }
+
+    fs::remove_file(item)
+}
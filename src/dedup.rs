@@ -0,0 +1,265 @@
+use crate::copy;
+use crate::progress::Progress;
+use crate::utils;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, Metadata},
+    io::{Error, ErrorKind, Write},
+    os::unix::fs::{lchown, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+/// Directory at the root of every store holding the deduplicated contents.
+/// Blobs are under `objects/`, named by the BLAKE3 checksum of their contents,
+/// and every trashed file with the same contents is a hard link to its blob
+pub const BLOBS_DIR: &str = ".trm-blobs";
+
+/// Attributes of a deduplicated file. The file shares its inode with every other copy,
+/// so its own attributes are kept here and put back when it is restored
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileAttrs {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: (i64, i64),
+    pub mtime: (i64, i64),
+}
+
+impl FileAttrs {
+    fn of(meta: &Metadata) -> FileAttrs {
+        FileAttrs {
+            mode: meta.mode(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            atime: (meta.atime(), meta.atime_nsec()),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+        }
+    }
+
+    fn apply(&self, path: &Path) -> Result<(), Error> {
+        match lchown(path, Some(self.uid), Some(self.gid)) {
+            Err(e) if copy::is_not_permitted(&e) => {}
+            result => result?,
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        copy::set_times(path, self.atime, self.mtime)
+    }
+}
+
+/// Deduplicated files of one trashed item, by their path relative to it
+pub type Manifest = BTreeMap<String, FileAttrs>;
+
+fn objects_dir(store: &Path) -> PathBuf {
+    store.join(BLOBS_DIR).join("objects")
+}
+
+fn manifest_path(store: &Path, trash_path: &Path) -> PathBuf {
+    let name = blake3::hash(trash_path.as_os_str().as_encoded_bytes()).to_hex();
+    store.join(BLOBS_DIR).join("manifests").join(format!("{}.json", name))
+}
+
+/// Whether a path is the blob directory of a store, which is never shown as trashed
+pub fn is_blobs_dir(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == BLOBS_DIR)
+}
+
+/// Times of the directories in `root` which hold the given files, so they can be put back
+/// after the files are replaced
+fn parent_times(root: &Path, files: &[PathBuf]) -> Result<HashMap<PathBuf, Metadata>, Error> {
+    let mut times: HashMap<PathBuf, Metadata> = HashMap::new();
+    for file in files {
+        if let Some(parent) = file.parent() {
+            if parent.starts_with(root) && !times.contains_key(parent) {
+                times.insert(parent.to_path_buf(), parent.symlink_metadata()?);
+            }
+        }
+    }
+    Ok(times)
+}
+
+fn restore_parent_times(times: &HashMap<PathBuf, Metadata>) -> Result<(), Error> {
+    for (dir, meta) in times {
+        copy::set_times(dir, (meta.atime(), meta.atime_nsec()), (meta.mtime(), meta.mtime_nsec()))?;
+    }
+    Ok(())
+}
+
+/// Store the files of an item just moved into `store` by their contents.
+///
+/// Every regular file whose contents are already in the store is replaced by a hard link
+/// to the existing blob, the others become blobs themselves. Files with hard links of their
+/// own or with extended attributes are left alone, as those cannot be kept per copy.
+/// Returns the number of bytes saved
+pub fn store_item(store: &Path, trash_path: &Path) -> Result<u64, Error> {
+    let mut candidates: Vec<(PathBuf, Metadata)> = vec![];
+    for entry in walkdir::WalkDir::new(trash_path).follow_root_links(false) {
+        let entry = entry.map_err(Error::from)?;
+        let meta = entry.path().symlink_metadata()?;
+        let has_xattrs = xattr::list(entry.path()).map_or(true, |mut names| names.next().is_some());
+        if meta.is_file()
+            && meta.len() > 0
+            && meta.nlink() == 1
+            && !has_xattrs
+            && entry.path().strip_prefix(trash_path).unwrap().to_str().is_some()
+        {
+            candidates.push((entry.into_path(), meta));
+        }
+    }
+
+    if candidates.is_empty() {
+        return Ok(0);
+    }
+
+    let name = get_file_name!(trash_path);
+    let total_bytes: u64 = candidates.iter().map(|(_, meta)| meta.len()).sum();
+    let progress = Progress::new(&format!("Deduplicating {}", name), total_bytes, candidates.len() as u64);
+    let hashes: Vec<String> = candidates
+        .par_iter()
+        .map(|(path, meta)| {
+            let hash = copy::hash_file(path)?;
+            progress.inc(meta.len(), 1);
+            Ok(hash)
+        })
+        .collect::<Result<_, Error>>()?;
+    drop(progress);
+
+    // the attributes are recorded before any file loses them
+    let manifest: Manifest = candidates
+        .iter()
+        .map(|(path, meta)| {
+            let relative_path = path.strip_prefix(trash_path).unwrap().to_str().unwrap().to_string();
+            (relative_path, FileAttrs::of(meta))
+        })
+        .collect();
+    write_manifest(&manifest_path(store, trash_path), &manifest)?;
+
+    let files: Vec<PathBuf> = candidates.iter().map(|(path, _)| path.clone()).collect();
+    let dir_times = parent_times(trash_path, &files)?;
+
+    let mut saved: u64 = 0;
+    for ((path, meta), hash) in candidates.iter().zip(&hashes) {
+        let blob = objects_dir(store).join(&hash[..2]).join(hash);
+        fs::create_dir_all(blob.parent().unwrap())?;
+        match fs::hard_link(path, &blob) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let tmp = utils::staging_path(path, std::process::id());
+                match fs::hard_link(&blob, &tmp) {
+                    Ok(_) => {
+                        fs::rename(&tmp, path)?;
+                        saved += meta.blocks() * 512;
+                    }
+                    // collected by a purge running at the same time, so keep this copy as it is
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    restore_parent_times(&dir_times)?;
+    Ok(saved)
+}
+
+fn write_manifest(path: &Path, manifest: &Manifest) -> Result<(), Error> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(serde_json::to_string(manifest)?.as_bytes())?;
+    file.sync_data()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// The deduplicated files of an item in the trash, with their own attributes. Empty if it has none
+pub fn manifest(store: &Path, trash_path: &Path) -> Result<Manifest, Error> {
+    match fs::read_to_string(manifest_path(store, trash_path)) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Manifest::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Give every deduplicated file of an item restored from `trash_path` to `restored` its own
+/// contents and attributes back, so changing it does not change the copies still in the trash
+pub fn restore_item(store: &Path, trash_path: &Path, restored: &Path) -> Result<(), Error> {
+    let manifest = manifest(store, trash_path)?;
+    if manifest.is_empty() {
+        return Ok(());
+    }
+
+    let files: Vec<PathBuf> = manifest
+        .keys()
+        .map(|relative_path| {
+            if relative_path.is_empty() {
+                restored.to_path_buf()
+            } else {
+                restored.join(relative_path)
+            }
+        })
+        .collect();
+    let dir_times = parent_times(restored, &files)?;
+
+    let name = get_file_name!(restored);
+    let progress = Progress::new(&format!("Unsharing {}", name), 0, files.len() as u64);
+    for (path, attrs) in files.iter().zip(manifest.values()) {
+        let meta = match path.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if !meta.is_file() {
+            continue;
+        }
+
+        // only files which were not hard links when trashed are in the manifest
+        if meta.nlink() > 1 {
+            let tmp = utils::staging_path(path, std::process::id());
+            copy::copy_file_contents(path, &tmp, &meta, &progress)?;
+            attrs.apply(&tmp)?;
+            fs::rename(&tmp, path)?;
+        } else {
+            attrs.apply(path)?;
+        }
+        progress.inc(0, 1);
+    }
+
+    restore_parent_times(&dir_times)?;
+    fs::remove_file(manifest_path(store, trash_path))
+}
+
+/// Drop what is recorded about an item which is being deleted from the trash
+pub fn forget(store: &Path, trash_path: &Path) {
+    let manifest_path = manifest_path(store, trash_path);
+    if manifest_path.exists() {
+        if let Err(e) = fs::remove_file(&manifest_path) {
+            eprintln!("Failed to remove {}: {}", manifest_path.display(), e);
+        }
+    }
+}
+
+/// Delete the blobs no trashed file links to anymore. Returns the number of bytes freed
+pub fn collect_garbage(store: &Path) -> u64 {
+    let objects_dir = objects_dir(store);
+    if !objects_dir.is_dir() {
+        return 0;
+    }
+
+    let mut freed: u64 = 0;
+    for entry in walkdir::WalkDir::new(&objects_dir).contents_first(true).into_iter().flatten() {
+        if entry.file_type().is_dir() {
+            // only succeeds once every blob in it is gone
+            let _ = fs::remove_dir(entry.path());
+            continue;
+        }
+        if let Ok(meta) = entry.path().symlink_metadata() {
+            if meta.nlink() == 1 && fs::remove_file(entry.path()).is_ok() {
+                freed += meta.blocks() * 512;
+            }
+        }
+    }
+    freed
+}
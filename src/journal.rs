@@ -0,0 +1,412 @@
+use crate::logging::{append_to_logs, read_logs, FileInfo, Filter, OpType};
+use crate::trm::{get_journal_dir, get_log_file, Args};
+use crate::{archive, crypt, dedup, store, utils};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Error, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+/// A single line of the write-ahead journal
+#[derive(Serialize, Deserialize, Debug)]
+enum JournalEntry {
+    /// Written when an operation starts. The time is reused as the `moved_time` of the log entry
+    Begin {
+        operation: OpType,
+        started_time: DateTime<Local>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reverts: Option<String>,
+    },
+
+    /// Written before a path is moved. `archive` is set when a compressed item is extracted instead,
+    /// `encrypted` when the item is encrypted into the trash
+    Intent {
+        src: String,
+        dst: String,
+        dst_existed: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        archive: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        encrypted: bool,
+    },
+
+    /// Written after a path has been moved
+    Moved { src: String, dst: String },
+
+    /// Written before a file to be overwritten by a restore is moved aside to `aside`
+    Aside { path: String, aside: String },
+}
+
+/// Write-ahead journal for one trash or restore operation.
+///
+/// Every move is recorded before it happens, so if `trm` is killed midway the next
+/// invocation can find out what was done and bring the log and the trash back in sync
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    pub operation: OpType,
+    pub started_time: DateTime<Local>,
+    /// ID of the operation being undone, recorded in the log entry
+    pub reverts: Option<String>,
+}
+
+impl Journal {
+    pub fn begin(operation: OpType, reverts: Option<String>) -> Result<Journal, Error> {
+        let dir = PathBuf::from(get_journal_dir());
+        utils::create_private_dir(&dir)?;
+
+        let path = dir.join(std::process::id().to_string());
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+
+        let mut journal = Journal {
+            path,
+            file,
+            operation,
+            started_time: Local::now(),
+            reverts,
+        };
+        journal.write(&JournalEntry::Begin {
+            operation: journal.operation.clone(),
+            started_time: journal.started_time,
+            reverts: journal.reverts.clone(),
+        })?;
+        Ok(journal)
+    }
+
+    pub fn intent(&mut self, src: &Path, dst: &Path) -> Result<(), Error> {
+        self.write(&JournalEntry::Intent {
+            src: src.display().to_string(),
+            dst: dst.display().to_string(),
+            dst_existed: utils::path_exists(dst),
+            archive: None,
+            encrypted: false,
+        })
+    }
+
+    pub fn encrypt_intent(&mut self, src: &Path, dst: &Path) -> Result<(), Error> {
+        self.write(&JournalEntry::Intent {
+            src: src.display().to_string(),
+            dst: dst.display().to_string(),
+            dst_existed: utils::path_exists(dst),
+            archive: None,
+            encrypted: true,
+        })
+    }
+
+    pub fn extract_intent(&mut self, src: &Path, dst: &Path, archive: &Path) -> Result<(), Error> {
+        self.write(&JournalEntry::Intent {
+            src: src.display().to_string(),
+            dst: dst.display().to_string(),
+            dst_existed: utils::path_exists(dst),
+            archive: Some(archive.display().to_string()),
+            encrypted: false,
+        })
+    }
+
+    pub fn moved(&mut self, src: &Path, dst: &Path) -> Result<(), Error> {
+        self.write(&JournalEntry::Moved {
+            src: src.display().to_string(),
+            dst: dst.display().to_string(),
+        })
+    }
+
+    pub fn aside(&mut self, path: &Path, aside: &Path) -> Result<(), Error> {
+        self.write(&JournalEntry::Aside {
+            path: path.display().to_string(),
+            aside: aside.display().to_string(),
+        })
+    }
+
+    /// The operation has been recorded in the log (or fully undone), so the journal is no longer needed
+    pub fn commit(self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove journal {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn write(&mut self, entry: &JournalEntry) -> Result<(), Error> {
+        let serialized_entry = serde_json::to_string(entry)?;
+        writeln!(self.file, "{}", serialized_entry)?;
+        self.file.sync_data()
+    }
+}
+
+/// What happened to a single path of an interrupted operation
+enum PathState {
+    NotMoved,
+    Moved,
+    /// A cross-device copy was put in place, but removing the original did not finish
+    SourceNotRemoved,
+    Unknown,
+}
+
+fn path_state(src: &Path, dst: &Path, dst_existed: bool, moved: bool) -> PathState {
+    if moved {
+        return PathState::Moved;
+    }
+
+    match (utils::path_exists(src), utils::path_exists(dst)) {
+        (true, false) => PathState::NotMoved,
+        (false, true) => PathState::Moved,
+        (true, true) if !dst_existed => PathState::SourceNotRemoved,
+        (true, true) => PathState::NotMoved,
+        (false, false) => PathState::Unknown,
+    }
+}
+
+fn process_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Replay the journals left behind by `trm` processes which did not finish.
+///
+/// Interrupted trash operations are rolled back, as a batch is all-or-nothing.
+/// Interrupted restore operations are rolled forward, recording what was already restored
+pub fn recover(args: &Args, dir_path: &Path) {
+    let dir = PathBuf::from(get_journal_dir());
+    if !utils::path_exists(&dir) {
+        return;
+    }
+    // anyone can create it in /tmp, so a journal is only trusted in a directory of the user
+    if let Err(e) = utils::create_private_dir(&dir) {
+        eprintln!("Ignoring the journals in {}: {}", dir.display(), e);
+        return;
+    }
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let trusted = path
+            .symlink_metadata()
+            .is_ok_and(|meta| meta.is_file() && utils::owned_by_user(&meta));
+        if !trusted {
+            continue;
+        }
+        let pid = match path.file_name().and_then(|f| f.to_str()).map(|f| f.parse::<u32>()) {
+            Some(Ok(pid)) => pid,
+            _ => continue,
+        };
+        if process_alive(pid) {
+            continue;
+        }
+
+        match replay(args, dir_path, &path, pid) {
+            Ok(true) => {
+                if let Err(e) = fs::remove_file(&path) {
+                    eprintln!("Failed to remove journal {}: {}", path.display(), e);
+                }
+            }
+            Ok(false) => {
+                eprintln!("Journal {} could not be fully replayed, will retry on next run", path.display());
+            }
+            Err(e) => {
+                eprintln!("Failed to replay journal {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Move a trashed item back, decrypting it if it was encrypted
+fn rollback_item(args: &Args, key: &mut Option<crypt::Key>, dst: &Path, src: &Path, encrypted: bool) -> Result<(), Error> {
+    if !encrypted {
+        return utils::move_content(&dst.to_path_buf(), &src.to_path_buf());
+    }
+    if key.is_none() {
+        *key = Some(crypt::try_load_key(args).map_err(Error::other)?);
+    }
+    crypt::decrypt_item(key.as_ref().unwrap(), dst, src)
+}
+
+/// Where a restored item went, as recorded in the log. Items whose original path is
+/// encrypted keep it encrypted, or hidden when there is no key
+fn restored_path(args: &Args, key: &mut Option<crypt::Key>, src: &Path, dst: &Path) -> String {
+    if !crypt::in_vault(src) {
+        return dst.display().to_string();
+    }
+    if key.is_none() {
+        *key = crypt::try_load_key(args).ok();
+    }
+    key.as_ref()
+        .and_then(|key| crypt::seal_path(key, &dst.display().to_string()).ok())
+        .unwrap_or_else(|| crypt::HIDDEN_PATH.to_string())
+}
+
+/// Returns whether the journal was fully resolved
+fn replay(args: &Args, dir_path: &Path, journal_path: &Path, pid: u32) -> Result<bool, Error> {
+    let reader = BufReader::new(File::open(journal_path)?);
+
+    let mut header: Option<(OpType, DateTime<Local>, Option<String>)> = None;
+    let mut intents: Vec<(PathBuf, PathBuf, bool, bool, bool)> = vec![];
+    let mut moved: Vec<(PathBuf, PathBuf)> = vec![];
+    let mut asides: Vec<(PathBuf, PathBuf)> = vec![];
+
+    for line in reader.lines() {
+        // a torn final line is expected if we were killed while writing it
+        let entry = match serde_json::from_str::<JournalEntry>(&line?) {
+            Ok(entry) => entry,
+            Err(_) => break,
+        };
+        match entry {
+            JournalEntry::Begin { operation, started_time, reverts } => {
+                header = Some((operation, started_time, reverts))
+            }
+            JournalEntry::Intent { src, dst, dst_existed, archive, encrypted } => intents.push((
+                PathBuf::from(src),
+                PathBuf::from(dst),
+                dst_existed,
+                archive.is_some(),
+                encrypted,
+            )),
+            JournalEntry::Moved { src, dst } => moved.push((PathBuf::from(src), PathBuf::from(dst))),
+            JournalEntry::Aside { path, aside } => asides.push((PathBuf::from(path), PathBuf::from(aside))),
+        }
+    }
+
+    let (operation, started_time, reverts) = match header {
+        Some(header) => header,
+        None => return Ok(true),
+    };
+
+    // the log entry was written, only removing the journal did not happen
+    let committed = Path::new(&get_log_file()).exists()
+        && read_logs(Filter::All).iter().any(|log| log.operation == operation && log.moved_time == started_time);
+    if committed {
+        return Ok(true);
+    }
+
+    if args.verbose || args.debug {
+        println!(
+            "Recovering interrupted {} operation started at {}",
+            operation.to_string().to_lowercase(),
+            started_time.to_rfc2822()
+        );
+    }
+
+    let stores = store::all_stores(dir_path);
+    let mut resolved = true;
+    let mut src_files: Vec<String> = vec![];
+    let mut dst_files: Vec<String> = vec![];
+
+    let mut extracted: Vec<String> = vec![];
+    let mut key: Option<crypt::Key> = None;
+
+    for (src, dst, dst_existed, from_archive, encrypted) in intents.iter().rev() {
+        // an unfinished cross-device copy never replaced anything, so it can just go
+        let staging = utils::staging_path(dst, pid);
+        if utils::path_exists(&staging) {
+            if let Err(e) = utils::remove_path(&staging) {
+                eprintln!("Failed to remove incomplete copy {}: {}", staging.display(), e);
+                resolved = false;
+            }
+        }
+
+        let was_moved = moved.iter().any(|(s, d)| s == src && d == dst);
+        let mut state = if *from_archive {
+            // the item is never in the trash itself, so only the extracted copy tells
+            if was_moved || (utils::path_exists(dst) && !dst_existed) {
+                PathState::Moved
+            } else {
+                PathState::NotMoved
+            }
+        } else {
+            path_state(src, dst, *dst_existed, was_moved)
+        };
+
+        if let PathState::SourceNotRemoved = state {
+            // the copy was verified before it was put in place, so finish the move
+            match utils::remove_path(src) {
+                Ok(_) => state = PathState::Moved,
+                Err(e) => {
+                    eprintln!("Failed to remove {} after it was copied: {}", src.display(), e);
+                    resolved = false;
+                    continue;
+                }
+            }
+        }
+
+        match state {
+            PathState::NotMoved | PathState::SourceNotRemoved => {}
+            PathState::Moved => match operation {
+                OpType::TRASH => match rollback_item(args, &mut key, dst, src, *encrypted) {
+                    Ok(_) => {
+                        eprintln!("Rolled back interrupted trash of {}", src.display());
+                        if let Some(store) = store::store_of(dst, &stores) {
+                            utils::remove_empty_parents(dst, store);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to roll back {}: {}", src.display(), e);
+                        resolved = false;
+                    }
+                },
+                OpType::RESTORE => {
+                    if let Some(store) = store::store_of(src, &stores) {
+                        if let Err(e) = dedup::restore_item(store, src, dst) {
+                            eprintln!("Failed to unshare {} from the trash: {}", dst.display(), e);
+                        }
+                    }
+                    if *from_archive {
+                        extracted.push(src.display().to_string());
+                    }
+                    src_files.insert(0, src.display().to_string());
+                    dst_files.insert(0, restored_path(args, &mut key, src, dst));
+                }
+            },
+            PathState::Unknown => {
+                eprintln!(
+                    "Neither {} nor {} exists, unable to recover it",
+                    src.display(),
+                    dst.display()
+                );
+            }
+        }
+    }
+
+    // a file which was to be overwritten goes if what replaces it made it, and comes back otherwise
+    for (path, aside) in &asides {
+        if !utils::path_exists(aside) {
+            continue;
+        }
+        let cleaned = match utils::path_exists(path) {
+            true => utils::remove_path(aside),
+            false => fs::rename(aside, path),
+        };
+        if let Err(e) = cleaned {
+            eprintln!("Failed to clean up {}: {}", aside.display(), e);
+            resolved = false;
+        }
+    }
+
+    if !src_files.is_empty() {
+        eprintln!("Recorded {} file(s) restored by an interrupted operation", src_files.len());
+        append_to_logs(&FileInfo {
+            src: src_files,
+            dst: dst_files,
+            operation: OpType::RESTORE,
+            moved_time: started_time,
+            meta: vec![],
+            archived: BTreeMap::new(),
+            encrypted: false,
+            reverts,
+        })?;
+        archive::forget_restored(&extracted)?;
+    }
+
+    Ok(resolved)
+}
@@ -0,0 +1,50 @@
+use crate::trm::get_lock_file;
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Error,
+    os::fd::AsRawFd,
+    process::exit,
+};
+
+/// Exclusive lock on the trash, held while it is changed.
+///
+/// Runs of `trm` take turns, so that a background compression never works on an item
+/// which is being restored, and rewrites of the log never lose an entry. Released on drop
+pub struct Lock {
+    _file: File,
+}
+
+fn open() -> Result<File, Error> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(get_lock_file())
+}
+
+fn flock(file: &File, operation: libc::c_int) -> Result<(), Error> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Wait until no other run of `trm` holds the lock, then take it
+pub fn acquire() -> Lock {
+    let result = open().and_then(|file| {
+        if flock(&file, libc::LOCK_EX | libc::LOCK_NB).is_err() {
+            eprintln!("Waiting for another trm to finish");
+            flock(&file, libc::LOCK_EX)?;
+        }
+        Ok(file)
+    });
+
+    match result {
+        Ok(file) => Lock { _file: file },
+        Err(e) => {
+            eprintln!("Failed to lock {}: {}", get_lock_file(), e);
+            exit(1);
+        }
+    }
+}
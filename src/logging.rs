@@ -1,14 +1,18 @@
 use crate::trm::{Args, get_log_file};
+use crate::plan::{Kind, Plan};
+use crate::progress::Progress;
+use crate::{archive, copy, crypt, dedup, store, utils};
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, TimeZone};
 
 use comfy_table::{modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, Table};
+use indicatif::HumanBytes;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Error, Write}, path::{Path, PathBuf}, process::exit
+    collections::{BTreeMap, HashMap, HashSet}, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Error, Write}, os::unix::fs::MetadataExt, path::{Path, PathBuf}, process::exit
 };
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum OpType {
     TRASH,
     RESTORE,
@@ -36,6 +40,284 @@ pub struct FileInfo {
 
     /// The datetime when it was moved
     pub moved_time: DateTime<Local>,
+
+    /// Metadata of every trashed item, in the same order as `src`. Empty for restores and older logs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub meta: Vec<ItemMeta>,
+
+    /// Trashed items which were compressed, from their path in the trash to the archive holding them
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub archived: BTreeMap<String, String>,
+
+    /// Whether the items were encrypted when they were trashed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+
+    /// ID of the operation this one reverts: the trash brought back by `trm undo`, or the restore trashed again by `trm redo`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverts: Option<String>,
+}
+
+/// Number of hex digits of an operation ID
+const ID_LEN: usize = 8;
+
+impl FileInfo {
+    /// Short ID of the operation, shown in `history` and taken by `undo` and `show`.
+    /// It is derived from the time of the operation, so entries of older versions have one too
+    pub fn id(&self) -> String {
+        let nanos = self.moved_time.timestamp_nanos_opt().unwrap_or_else(|| self.moved_time.timestamp());
+        let hash = blake3::hash(format!("{}:{}", self.operation.to_string(), nanos).as_bytes());
+        hash.to_hex()[..ID_LEN].to_string()
+    }
+}
+
+/// Index of the operation with the given ID, or a unique prefix of it
+pub fn find_operation(logs: &[FileInfo], id: &str) -> Result<usize, String> {
+    let id = id.to_lowercase();
+    if id.is_empty() {
+        return Err("No operation ID given".to_string());
+    }
+    let matches: Vec<usize> = (0..logs.len()).filter(|&index| logs[index].id().starts_with(&id)).collect();
+    match matches[..] {
+        [index] => Ok(index),
+        [] => Err(format!("No operation with ID {}", id)),
+        _ => Err(format!("ID {} is ambiguous, give more of it", id)),
+    }
+}
+
+/// Birth time of a file, or the time of its last status change, which is never earlier
+fn birth_time(meta: &fs::Metadata) -> DateTime<Local> {
+    match meta.created() {
+        Ok(created) => DateTime::from(created),
+        Err(_) => Local.timestamp_nanos(meta.ctime() * 1_000_000_000 + meta.ctime_nsec()),
+    }
+}
+
+/// Metadata of a trashed item, captured just before it was moved
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemMeta {
+    /// Apparent size in bytes, of the whole tree for directories
+    pub size: u64,
+
+    /// Space taken up on disk, of the whole tree for directories
+    pub disk_usage: u64,
+
+    /// One of `file`, `dir`, `symlink` or `other`
+    pub file_type: String,
+
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: DateTime<Local>,
+    pub ino: u64,
+    pub dev: u64,
+
+    /// BLAKE3 checksum of the contents, only recorded when asked for with --hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+
+    /// When the item was created, or last changed where the filesystem does not record that.
+    /// Missing from entries written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub birth_time: Option<DateTime<Local>>,
+}
+
+impl ItemMeta {
+    pub fn capture(path: &Path, hash: bool) -> Result<ItemMeta, Error> {
+        let meta = path.symlink_metadata()?;
+        let stats = copy::tree_stats(path)?;
+
+        let file_type = if meta.is_symlink() {
+            "symlink"
+        } else if meta.is_dir() {
+            "dir"
+        } else if meta.is_file() {
+            "file"
+        } else {
+            "other"
+        };
+
+        Ok(ItemMeta {
+            size: stats.bytes,
+            disk_usage: stats.disk_usage,
+            file_type: file_type.to_string(),
+            mode: meta.mode(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            mtime: DateTime::from(meta.modified()?),
+            ino: meta.ino(),
+            dev: meta.dev(),
+            hash: if hash { Some(copy::hash_tree(path)?) } else { None },
+            birth_time: Some(birth_time(&meta)),
+        })
+    }
+
+    /// Permissions in the form `ls -l` shows them, e.g. `rwxr-xr-x`
+    pub fn permissions(&self) -> String {
+        (0..9)
+            .map(|bit| {
+                if self.mode & (0o400 >> bit) == 0 {
+                    '-'
+                } else {
+                    ['r', 'w', 'x'][bit % 3]
+                }
+            })
+            .collect()
+    }
+}
+
+/// Metadata of everything currently in the trash, keyed by its path in the trash.
+/// When a path was trashed more than once, the latest entry wins
+pub fn trashed_items() -> HashMap<String, ItemMeta> {
+    let mut items: HashMap<String, ItemMeta> = HashMap::new();
+    if !Path::new(&get_log_file()).exists() {
+        return items;
+    }
+
+    for log in read_logs(Filter::All) {
+        if log.operation != OpType::TRASH {
+            continue;
+        }
+        for (dst, meta) in log.dst.into_iter().zip(log.meta) {
+            items.insert(dst, meta);
+        }
+    }
+    items
+}
+
+/// The entry every path in the trash belongs to, as an index into `logs`.
+/// A path can be trashed, restored and trashed again, in which case it belongs to the latest entry
+pub fn owners(logs: &[FileInfo]) -> HashMap<&String, usize> {
+    let mut owner: HashMap<&String, usize> = HashMap::new();
+    for (index, log) in logs.iter().enumerate() {
+        if log.operation == OpType::TRASH {
+            for dst in &log.dst {
+                owner.insert(dst, index);
+            }
+        }
+    }
+    owner
+}
+
+/// An item which is still in the trash, as the log tells
+pub struct TrashedItem {
+    pub dst: PathBuf,
+    pub original: PathBuf,
+    /// ID of the operation which trashed it
+    pub id: String,
+    pub trashed: DateTime<Local>,
+    pub meta: Option<ItemMeta>,
+    pub compressed: bool,
+}
+
+/// Items still in the trash with their original path, from the oldest to the latest trashed.
+/// A path trashed more than once has an item for every version of it. Items whose original
+/// path is encrypted are found with `crypt::vault_items` instead
+pub fn trashed_originals() -> Vec<TrashedItem> {
+    if !Path::new(&get_log_file()).exists() {
+        return vec![];
+    }
+
+    let logs = read_logs(Filter::All);
+    let owner = owners(&logs);
+    let mut items: Vec<TrashedItem> = vec![];
+    for (index, log) in logs.iter().enumerate() {
+        if log.operation != OpType::TRASH {
+            continue;
+        }
+        for (i, (src, dst)) in log.src.iter().zip(&log.dst).enumerate() {
+            let compressed = log.archived.contains_key(dst);
+            let in_trash = compressed || utils::path_exists(Path::new(dst));
+            if crypt::is_sealed(src) || owner.get(dst) != Some(&index) || !in_trash {
+                continue;
+            }
+            items.push(TrashedItem {
+                dst: PathBuf::from(dst),
+                original: PathBuf::from(src),
+                id: log.id(),
+                trashed: log.moved_time,
+                meta: log.meta.get(i).cloned(),
+                compressed,
+            });
+        }
+    }
+    items
+}
+
+/// Items which were under `dir` at `at` and were trashed since, as (original, place in the trash, id)
+/// in the order they were trashed. Items already in the trash at `at`, and items created
+/// after it, are left out
+pub fn trashed_since<'a>(
+    logs: &'a [FileInfo],
+    dir: &Path,
+    at: DateTime<Local>,
+) -> Vec<(&'a String, &'a String, String)> {
+    // original path of what each place in the trash holds
+    let mut held: HashMap<&String, &String> = HashMap::new();
+    let mut in_trash_at: HashSet<&String> = HashSet::new();
+    let mut trashed: Vec<(&String, &String, String)> = vec![];
+    for log in logs {
+        let before = log.moved_time <= at;
+        match log.operation {
+            OpType::TRASH => {
+                for (i, (src, dst)) in log.src.iter().zip(&log.dst).enumerate() {
+                    let born_after = log.meta.get(i).and_then(|meta| meta.birth_time).is_some_and(|born| born > at);
+                    if before {
+                        in_trash_at.insert(src);
+                    } else if Path::new(src).starts_with(dir) && !in_trash_at.contains(src) && !born_after {
+                        trashed.push((src, dst, log.id()));
+                    }
+                    held.insert(dst, src);
+                }
+            }
+            OpType::RESTORE if before => {
+                for src in &log.src {
+                    if let Some(original) = held.get(src) {
+                        in_trash_at.remove(original);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    trashed
+}
+
+/// Paths in the trash holding an encrypted item. When a path was trashed more than once,
+/// the latest entry decides
+pub fn encrypted_items() -> HashSet<String> {
+    let mut items: HashSet<String> = HashSet::new();
+    if !Path::new(&get_log_file()).exists() {
+        return items;
+    }
+
+    for log in read_logs(Filter::All) {
+        if log.operation != OpType::TRASH {
+            continue;
+        }
+        for dst in log.dst {
+            if log.encrypted {
+                items.insert(dst);
+            } else {
+                items.remove(&dst);
+            }
+        }
+    }
+    items
+}
+
+/// Compressed items still waiting to be restored, from their path in the trash to their archive.
+/// When a path was compressed more than once, the latest entry wins
+pub fn archived_items() -> HashMap<String, String> {
+    let mut items: HashMap<String, String> = HashMap::new();
+    if !Path::new(&get_log_file()).exists() {
+        return items;
+    }
+
+    for log in read_logs(Filter::All) {
+        items.extend(log.archived);
+    }
+    items
 }
 
 pub fn append_to_logs(info: &FileInfo) -> Result<(), Error> {
@@ -58,7 +340,7 @@ fn generate_table() -> Table{
     table
         .load_preset(UTF8_FULL)
         .apply_modifier(UTF8_ROUND_CORNERS)
-        .set_header(vec!["Time", "Operation", "Source", "Destination"]);
+        .set_header(vec!["ID", "Time", "Operation", "Source", "Destination", "Size"]);
     table
 }
 /// Filter is used for filtering the logs based on what we want
@@ -68,7 +350,7 @@ pub enum Filter{
     Before(DateTime<Local>)
 }
 
-fn read_logs(filter: Filter) -> Vec<FileInfo> {
+pub fn read_logs(filter: Filter) -> Vec<FileInfo> {
     let file = match File::open(get_log_file()){
         Ok(file ) => file,
         Err(e) => {
@@ -118,7 +400,7 @@ fn read_logs(filter: Filter) -> Vec<FileInfo> {
     logs
 }
 
-pub fn display_logs(filter: Filter){
+pub fn display_logs(filter: Filter, args: &Args){
     let mut table = generate_table();
     let logs = read_logs(filter);
 
@@ -126,14 +408,25 @@ pub fn display_logs(filter: Filter){
         eprintln!("No history to show");
         exit(1);
     }
+    let key = crypt::history_key(args, &logs);
+    let key = key.as_ref();
 
 
     for log in logs {
+        let sizes: Vec<String> = log.meta.iter().map(|meta| HumanBytes(meta.size).to_string()).collect();
         table.add_row(vec![
+            log.id(),
             log.moved_time.to_rfc2822(),
             log.operation.to_string(),
-            log.src.join("\n"),
-            log.dst.join("\n")
+            log.src.iter().map(|src| crypt::reveal_path(src, key)).collect::<Vec<String>>().join("\n"),
+            log.dst.iter().map(|dst| {
+                if log.archived.contains_key(dst) {
+                    format!("{} (compressed)", dst)
+                } else {
+                    crypt::reveal_path(dst, key)
+                }
+            }).collect::<Vec<String>>().join("\n"),
+            sizes.join("\n")
             ]
         );
     }
@@ -141,8 +434,65 @@ pub fn display_logs(filter: Filter){
     println!("{}", table);
 }
 
+/// Show every item of one operation and where it is now
+pub fn display_operation(args: &Args, id: &str) {
+    if !Path::new(&get_log_file()).exists() {
+        eprintln!("No history to show");
+        exit(1);
+    }
+    let logs = read_logs(Filter::All);
+    let index = match find_operation(&logs, id) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let log = &logs[index];
+    let owner = owners(&logs);
+    let key = crypt::history_key(args, std::slice::from_ref(log));
+    let key = key.as_ref();
+
+    println!("Operation {}: {}, {}", log.id(), log.operation.to_string(), log.moved_time.to_rfc2822());
+
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_header(vec!["Source", "Destination", "Type", "Size", "Status"]);
+
+    for (i, (src, dst)) in log.src.iter().zip(&log.dst).enumerate() {
+        let status = if log.operation == OpType::RESTORE {
+            "restored"
+        } else if log.archived.contains_key(dst) {
+            "in trash, compressed"
+        } else if owner.get(dst) != Some(&index) {
+            "trashed again later"
+        } else if utils::path_exists(Path::new(dst)) {
+            "in trash"
+        } else if logs[index + 1..]
+            .iter()
+            .any(|later| later.operation == OpType::RESTORE && later.src.contains(dst))
+        {
+            "restored"
+        } else {
+            "purged"
+        };
+        let meta = log.meta.get(i);
+        table.add_row(vec![
+            crypt::reveal_path(src, key),
+            crypt::reveal_path(dst, key),
+            meta.map_or(String::new(), |meta| meta.file_type.clone()),
+            meta.map_or(String::new(), |meta| HumanBytes(meta.size).to_string()),
+            status.to_string(),
+        ]);
+    }
+
+    println!("{}", table);
+}
+
 /// Purge old files in trash and also remove corresponding entries in log
-pub fn purge_logs(args: &Args, cutoff: DateTime<Local>, quiet: bool){
+pub fn purge_logs(args: &Args, dir_path: &Path, cutoff: DateTime<Local>, quiet: bool){
     let file = match File::open(get_log_file()){
         Ok(file ) => file,
         Err(e) => {
@@ -153,6 +503,9 @@ pub fn purge_logs(args: &Args, cutoff: DateTime<Local>, quiet: bool){
 
     let mut to_be_deleted_files: Vec<PathBuf> = vec![];
     let mut new_logs: Vec<FileInfo> = vec![];
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+    let mut compressed_files: Vec<PathBuf> = vec![];
+    let mut archived_logs: Vec<FileInfo> = vec![];
 
     let reader = BufReader::new(file);
     for line in reader.lines(){
@@ -168,9 +521,17 @@ pub fn purge_logs(args: &Args, cutoff: DateTime<Local>, quiet: bool){
                 if log.operation == OpType::RESTORE{
                     continue;
                 } 
-                for dst in log.dst{
+                let mut meta = log.meta.iter();
+                for dst in &log.dst{
                     let dst = PathBuf::from(dst);
-                    if !dst.exists(){
+                    if let Some(meta) = meta.next(){
+                        sizes.insert(dst.clone(), meta.size);
+                    }
+                    if log.archived.contains_key(&dst.display().to_string()){
+                        compressed_files.push(dst);
+                        continue;
+                    }
+                    if !utils::path_exists(&dst){
                         if args.verbose{
                             println!("Path {} does not exist. Skipping", dst.display());
                         }
@@ -180,16 +541,45 @@ pub fn purge_logs(args: &Args, cutoff: DateTime<Local>, quiet: bool){
                     to_be_deleted_files.push(dst);
 
                 }
+                if !log.archived.is_empty(){
+                    archived_logs.push(log);
+                }
             } else{
                 new_logs.push(log);
             }
         }
     }
 
-    if !quiet && !to_be_deleted_files.is_empty(){
+    if args.dry_run{
+        let mut plan = Plan::new();
+        let size_note = |file: &PathBuf| sizes.get(file).map(|size| HumanBytes(*size).to_string());
+        for file in &to_be_deleted_files{
+            plan.add(Kind::Delete, file, None, size_note(file).into_iter().collect());
+        }
+        for file in &compressed_files{
+            let notes = size_note(file).into_iter().chain(["compressed".to_string()]).collect();
+            plan.add(Kind::Delete, file, None, notes);
+        }
+        plan.print(args);
+        return;
+    }
+
+    if !quiet && (!to_be_deleted_files.is_empty() || !compressed_files.is_empty()){
         let mut input = String::new();
         for file in &to_be_deleted_files{
-            println!("{}", file.display());
+            match sizes.get(file){
+                Some(size) => println!("{} ({})", file.display(), HumanBytes(*size)),
+                None => println!("{}", file.display()),
+            }
+        }
+        for file in &compressed_files{
+            match sizes.get(file){
+                Some(size) => println!("{} ({}, compressed)", file.display(), HumanBytes(*size)),
+                None => println!("{} (compressed)", file.display()),
+            }
+        }
+        if !sizes.is_empty(){
+            println!("Total: {}", HumanBytes(sizes.values().sum()));
         }
         print!("The above files will be deleted. Do you want to continue? [y/N]: ");
         io::stdout().flush().unwrap();
@@ -201,44 +591,173 @@ pub fn purge_logs(args: &Args, cutoff: DateTime<Local>, quiet: bool){
     }
 
     // deleting the files
+    let (total_bytes, total_entries) = to_be_deleted_files
+        .iter()
+        .filter_map(|dst| copy::tree_stats(dst).ok())
+        .fold((0, 0), |(bytes, entries), stats| (bytes + stats.bytes, entries + stats.entries));
+    let progress = Progress::new("Purging", total_bytes, total_entries);
+    let stores = store::all_stores(dir_path);
+
     for dst in &to_be_deleted_files{
+        let store = store::store_of(dst, &stores);
+        if let Some(store) = store{
+            dedup::forget(store, dst);
+        }
         // Delete the file/directory
-        let mut curr_parent = dst.parent().unwrap();
-        if dst.is_dir(){
-            if let Err(e) = fs::remove_dir_all(&dst){
-                eprintln!("Error deleting directory {}: {}", dst.display(), e);
-            } else if args.verbose{
-                println!("Removed {}", dst.display());
-            }
+        if let Err(e) = copy::remove_tree(dst, &progress){
+            eprintln!("Error deleting {}: {}", dst.display(), e);
+        } else if args.verbose{
+            println!("Removed {}", dst.display());
         }
-        else{
-            if let Err(e) = fs::remove_file(&dst){
-                eprintln!("Error deleting file {}: {}", dst.display(), e);
-            } else if args.verbose{
-                println!("Removed {}", dst.display());
-            }
+        // the store itself and the directories above it stay
+        if let Some(store) = store{
+            utils::remove_empty_parents(dst, store);
         }
-        while curr_parent.exists(){
-            let new_parent = curr_parent.parent().unwrap_or_else(|| Path::new(""));
-            if let Err(_) = fs::remove_dir(curr_parent){
-                break;
-            }
-            curr_parent = new_parent;
+    }
+
+    for log in &archived_logs{
+        archive::remove_archives(log);
+        if args.verbose{
+            println!("Removed compressed items trashed {}", log.moved_time.to_rfc2822());
         }
     }
 
+    // contents still shared with items left in the trash are kept
+    drop(progress);
+    for store in &stores{
+        dedup::collect_garbage(store);
+    }
+
     // write new log to file
-    let file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(get_log_file())
-        .unwrap();
+    if let Err(e) = write_logs(&new_logs){
+        eprintln!("Failed to write logs: {}", e);
+        exit(1);
+    }
+}
+
+/// Replace the whole log with the given entries.
+/// The new log is written next to the old one and renamed over it, so it is never left half written
+pub fn write_logs<T: std::borrow::Borrow<FileInfo>>(logs: &[T]) -> Result<(), Error> {
+    let tmp_log_file = format!("{}.tmp", get_log_file());
+    let file = File::create(&tmp_log_file)?;
 
     let mut writer = io::BufWriter::new(file);
-    for log in new_logs{
-        let serialized_info = serde_json::to_string(&log).unwrap();
-        writeln!(writer, "{}", serialized_info).unwrap();
+    for log in logs{
+        let serialized_info = serde_json::to_string(log.borrow())?;
+        writeln!(writer, "{}", serialized_info)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+
+    fs::rename(&tmp_log_file, get_log_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(operation: OpType, nanos: i64) -> FileInfo {
+        FileInfo {
+            src: vec![],
+            dst: vec![],
+            operation,
+            moved_time: Local.timestamp_nanos(nanos),
+            meta: vec![],
+            archived: BTreeMap::new(),
+            encrypted: false,
+            reverts: None,
+        }
+    }
+
+    fn logs() -> Vec<FileInfo> {
+        (0..20).map(|i| entry(if i % 2 == 0 { OpType::TRASH } else { OpType::RESTORE }, i * 1_000_000)).collect()
+    }
+
+    #[test]
+    fn ids_are_stable() {
+        let log = entry(OpType::TRASH, 42);
+        assert_eq!(log.id().len(), ID_LEN);
+        assert!(log.id().chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(log.id(), entry(OpType::TRASH, 42).id());
+        assert_ne!(log.id(), entry(OpType::RESTORE, 42).id());
     }
-    writer.flush().unwrap();
 
-}
\ No newline at end of file
+    #[test]
+    fn find_by_id_or_prefix() {
+        let logs = logs();
+        for (index, log) in logs.iter().enumerate() {
+            assert_eq!(find_operation(&logs, &log.id()), Ok(index));
+            assert_eq!(find_operation(&logs, &log.id().to_uppercase()), Ok(index));
+        }
+
+        let id = logs[3].id();
+        let prefix = (1..=ID_LEN)
+            .map(|len| &id[..len])
+            .find(|prefix| logs.iter().filter(|log| log.id().starts_with(prefix)).count() == 1)
+            .unwrap();
+        assert_eq!(find_operation(&logs, prefix), Ok(3));
+    }
+
+    #[test]
+    fn find_missing_or_ambiguous() {
+        let logs = logs();
+        assert!(find_operation(&logs, "").is_err());
+        assert!(find_operation(&logs, "xyz").is_err());
+        assert!(find_operation(&logs, &format!("{}0", logs[0].id())).is_err());
+
+        // there are more entries than hex digits, so two of them start alike
+        let shared = logs
+            .iter()
+            .map(|log| log.id()[..1].to_string())
+            .find(|digit| logs.iter().filter(|log| log.id().starts_with(digit)).count() > 1)
+            .unwrap();
+        assert!(find_operation(&logs, &shared).unwrap_err().contains("ambiguous"));
+    }
+
+    /// A trash entry of files born at the given times
+    fn trashed(nanos: i64, files: &[(&str, i64)]) -> FileInfo {
+        let mut log = entry(OpType::TRASH, nanos);
+        for (src, born) in files {
+            log.src.push(src.to_string());
+            log.dst.push(format!("/trash{}-{}", src, nanos));
+            log.meta.push(ItemMeta {
+                size: 0,
+                disk_usage: 0,
+                file_type: "file".to_string(),
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                mtime: Local.timestamp_nanos(*born),
+                ino: 0,
+                dev: 0,
+                hash: None,
+                birth_time: Some(Local.timestamp_nanos(*born)),
+            });
+        }
+        log
+    }
+
+    #[test]
+    fn trashed_since_leaves_out_items_created_after() {
+        let at = Local.timestamp_nanos(100);
+        let logs = vec![
+            trashed(50, &[("/project/old", 10)]),
+            trashed(150, &[("/project/a", 20), ("/project/new", 120), ("/elsewhere/b", 30)]),
+            trashed(200, &[("/project/old", 160)]),
+        ];
+        let items: Vec<&String> = trashed_since(&logs, Path::new("/project"), at)
+            .into_iter()
+            .map(|(src, _, _)| src)
+            .collect();
+        assert_eq!(items, vec!["/project/a"]);
+    }
+
+    #[test]
+    fn trashed_since_keeps_items_without_birth_time() {
+        let mut log = trashed(150, &[("/project/a", 120)]);
+        log.meta[0].birth_time = None;
+        let logs = vec![log];
+        assert_eq!(trashed_since(&logs, Path::new("/project"), Local.timestamp_nanos(100)).len(), 1);
+    }
+}
@@ -1,22 +1,61 @@
 #[macro_use]
 mod utils;
+mod archive;
+mod config;
+mod conflict;
+mod copy;
+mod crypt;
+mod dedup;
+mod journal;
+mod lock;
 mod logging;
+mod plan;
+mod progress;
+mod prompt;
+mod protect;
+mod rm_compat;
+mod quota;
+mod store;
 mod trm;
 
 use chrono::{Local, Duration};
 use clap::Parser;
-use logging::{display_logs, purge_logs, Filter};
+use logging::{display_logs, display_operation, purge_logs, Filter};
 use std::{path::PathBuf, process::exit};
-use trm::{list_all_files, recover_all_files, list_delete_files, move_files, recover_files, Args, Commands};
+use trm::{list_all_files, recover_all_files, list_delete_files, move_files, recover_files, redo_operation, restore_at, trashed_versions, undo_operation, undo_recent, Args, Commands};
 
 fn main() {
-    let args = Args::parse();
+    let config = config::load();
+    let rm_args = if rm_compat::selected(config.rm_compat) {
+        Some(rm_compat::RmArgs::parse_from(rm_compat::argv()))
+    } else {
+        None
+    };
+    let mut args = match &rm_args {
+        Some(rm_args) => rm_args.to_args(),
+        None => Args::parse_from(rm_compat::argv()),
+    };
+    config.apply(&mut args);
+    if rm_args.is_some() {
+        // rm asks by its own rules, and never with -f
+        args.interactive_once = false;
+    }
+
+    // like `xargs -0`, -0 alone reads the list from stdin
+    if args.null && args.from_file.is_none() {
+        args.stdin = true;
+    }
 
     if let Err(e) = args.validate(){
         eprintln!("Error validating args: {}", e);
         exit(1);
     }
 
+    if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(args.jobs).build_global() {
+        eprintln!("Could not start {} threads: {}", args.jobs, e);
+        exit(1);
+    }
+
 
     let mut files: Vec<PathBuf> = vec![];
 
@@ -28,6 +67,21 @@ fn main() {
         files.push(PathBuf::from(file));
     }
 
+    if args.stdin || args.from_file.is_some() {
+        match utils::read_file_list(&args) {
+            Ok(list) => files.extend(list),
+            Err(e) => {
+                eprintln!("Failed to read the list of files: {}", e);
+                exit(1);
+            }
+        }
+        files = utils::prune_nested(files);
+        if files.is_empty() {
+            eprintln!("No files given");
+            exit(1);
+        }
+    }
+
     let dir_path = match utils::setup_directory(&args) {
         Ok(path) => path,
         Err(e) => {
@@ -36,13 +90,35 @@ fn main() {
         }
     };
 
+    // compression takes the lock one operation at a time, so that it can run in the background
+    if let Some(Commands::Compress { after }) = args.command {
+        match after.or(args.compress_after) {
+            Some(after) => archive::compress(&args, &dir_path, after),
+            None => {
+                eprintln!("No age given, use --after or set compress_after in the config file");
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    // a dry run changes nothing, so it neither takes the lock nor puts right an interrupted operation
+    let _lock = if args.dry_run { None } else { Some(lock::acquire()) };
+    if !args.dry_run {
+        journal::recover(&args, &dir_path);
+    }
+
+    if let Some(rm_args) = &rm_args {
+        rm_compat::remove(rm_args, &args, &dir_path);
+    }
+
     if args.list && args.undo {
         let deleted_files = list_delete_files(&args, &dir_path, &mut files, true).unwrap();
         let mut flattened_files: Vec<PathBuf> = deleted_files.into_iter().flatten().collect();
-        recover_files(&args, &dir_path, &mut flattened_files, true);
+        recover_files(&args, &dir_path, &mut flattened_files, true, None);
     } 
     else if args.list && args.all{
-        list_all_files(&dir_path, false);
+        list_all_files(&args, &dir_path, false);
     }
     else if args.undo && args.all{
         recover_all_files(&args, &dir_path);
@@ -54,49 +130,67 @@ fn main() {
         }
     } 
     else if args.undo {
-        recover_files(&args, &dir_path, &mut files, false);
+        recover_files(&args, &dir_path, &mut files, false, None);
     } 
-    else if let Some(Commands::History {all, before, path}) = args.command {
-        if all{
-            display_logs(Filter::All);
+    else if let Some(Commands::History {all, before, path}) = &args.command {
+        if *all{
+            display_logs(Filter::All, &args);
         } 
-        else if let Some(before_duration) = before{
+        else if let Some(before_duration) = *before{
             let now = Local::now();
             let before_time = Duration::seconds(before_duration.as_secs() as i64);
             let cutoff = now - before_time;
-            display_logs(Filter::Before(cutoff));
+            display_logs(Filter::Before(cutoff), &args);
         } 
         else if !path.is_empty(){
             let path = PathBuf::from(path);
             if !path.exists(){
                 eprintln!("Path does not exist: {}", path.display());
             }
-            display_logs(Filter::Prefix(path));
+            display_logs(Filter::Prefix(path), &args);
         }
 
         else{
             let cwd = std::env::current_dir().unwrap();
-            display_logs(Filter::Prefix(cwd));
+            display_logs(Filter::Prefix(cwd), &args);
         }
     } 
+    else if let Some(Commands::Undo { id, last, since }) = &args.command {
+        match id {
+            Some(id) => undo_operation(&args, &dir_path, id),
+            None => undo_recent(&args, &dir_path, *last, *since),
+        }
+    }
+    else if let Some(Commands::Redo { id }) = &args.command {
+        redo_operation(&args, &dir_path, id.as_deref());
+    }
+    else if let Some(Commands::Versions { path, restore }) = &args.command {
+        trashed_versions(&args, &dir_path, path, *restore);
+    }
+    else if let Some(Commands::Restore { at, dir }) = &args.command {
+        restore_at(&args, &dir_path, dir, *at);
+    }
+    else if let Some(Commands::Show { id }) = &args.command {
+        display_operation(&args, id);
+    }
     else if let Some(Commands::Purge { before , quiet, all}) = args.command{
         let now = Local::now();
 
         if all{
-            purge_logs(&args, now, quiet);
+            purge_logs(&args, &dir_path, now, quiet);
             return;
         }
 
         if let Some(before_duration) = before{
             let before_time = Duration::seconds(before_duration.as_secs() as i64);
             let cutoff = now - before_time;
-            purge_logs(&args, cutoff, quiet);
+            purge_logs(&args, &dir_path, cutoff, quiet);
         } else{
             eprintln!("No cutoff time provided");
             exit(1);
         }
     }
     else {
-        move_files(&args, &dir_path, &files);
+        move_files(&args, &dir_path, &files, None);
     }
 }
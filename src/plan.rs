@@ -0,0 +1,78 @@
+use crate::trm::Args;
+
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Trash,
+    Restore,
+    Delete,
+    Skip,
+}
+
+/// A single change a --dry-run found would be made
+#[derive(Serialize, Debug)]
+pub struct Action {
+    pub action: Kind,
+    pub src: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst: Option<String>,
+    /// Details worth knowing before going ahead, e.g. that an existing file would be replaced
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<String>,
+}
+
+/// The changes of a --dry-run, in the order they would be made
+#[derive(Default)]
+pub struct Plan {
+    actions: Vec<Action>,
+}
+
+impl Plan {
+    pub fn new() -> Plan {
+        Plan::default()
+    }
+
+    pub fn add(&mut self, action: Kind, src: &Path, dst: Option<&Path>, notes: Vec<String>) {
+        self.actions.push(Action {
+            action,
+            src: src.display().to_string(),
+            dst: dst.map(|dst| dst.display().to_string()),
+            notes,
+        });
+    }
+
+    /// Show the plan, as JSON with --json. Nothing has been changed at this point
+    pub fn print(&self, args: &Args) {
+        if args.json {
+            match serde_json::to_string_pretty(&self.actions) {
+                Ok(json) => println!("{}", json),
+                Err(e) => eprintln!("Failed to serialize the plan: {}", e),
+            }
+            return;
+        }
+
+        for action in &self.actions {
+            let verb = match action.action {
+                Kind::Trash => "trash",
+                Kind::Restore => "restore",
+                Kind::Delete => "delete",
+                Kind::Skip => "skip",
+            };
+            let mut line = format!("Would {} {}", verb, action.src);
+            if let Some(dst) = &action.dst {
+                line.push_str(&format!(" -> {}", dst));
+            }
+            if !action.notes.is_empty() {
+                line.push_str(&format!(" ({})", action.notes.join(", ")));
+            }
+            println!("{}", line);
+        }
+        if self.actions.is_empty() {
+            println!("Nothing to do");
+        }
+        eprintln!("Dry run, nothing was changed");
+    }
+}
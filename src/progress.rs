@@ -0,0 +1,151 @@
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{
+    io::IsTerminal,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long an operation has to run before any progress is shown
+const SHOW_AFTER: Duration = Duration::from_secs(1);
+
+/// How often a progress line is printed when stderr is not a terminal
+const LINE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Progress of a long running copy, restore or purge.
+///
+/// On a terminal this is a progress bar on stderr, otherwise a line is printed every few seconds.
+/// Nothing is shown for operations that finish quickly. When `total_bytes` is 0, only entries are tracked
+pub struct Progress {
+    label: String,
+    total_bytes: u64,
+    total_entries: u64,
+    bytes: AtomicU64,
+    entries: AtomicU64,
+    started: Instant,
+    is_terminal: bool,
+    bar: OnceLock<ProgressBar>,
+    last_line: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    pub fn new(label: &str, total_bytes: u64, total_entries: u64) -> Progress {
+        Progress {
+            label: label.to_string(),
+            total_bytes,
+            total_entries,
+            bytes: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            started: Instant::now(),
+            is_terminal: std::io::stderr().is_terminal(),
+            bar: OnceLock::new(),
+            last_line: Mutex::new(None),
+        }
+    }
+
+    /// Record that `bytes` more bytes and `entries` more entries are done
+    pub fn inc(&self, bytes: u64, entries: u64) {
+        let bytes = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let entries = self.entries.fetch_add(entries, Ordering::Relaxed) + entries;
+
+        if self.started.elapsed() < SHOW_AFTER {
+            return;
+        }
+
+        if self.is_terminal {
+            let bar = self.bar.get_or_init(|| self.make_bar());
+            if self.total_bytes > 0 {
+                bar.set_position(bytes);
+                bar.set_message(format!("{}/{} entries", entries, self.total_entries));
+            } else {
+                bar.set_position(entries);
+            }
+            return;
+        }
+
+        let mut last_line = self.last_line.lock().unwrap();
+        if last_line.is_none_or(|time| time.elapsed() >= LINE_INTERVAL) {
+            *last_line = Some(Instant::now());
+            eprintln!("{}", self.line(bytes, entries));
+        }
+    }
+
+    fn make_bar(&self) -> ProgressBar {
+        let (length, template) = if self.total_bytes > 0 {
+            (
+                self.total_bytes,
+                "{prefix} [{bar:30}] {bytes}/{total_bytes}, {msg} ({eta} left)",
+            )
+        } else {
+            (self.total_entries, "{prefix} [{bar:30}] {pos}/{len} entries ({eta} left)")
+        };
+
+        let bar = ProgressBar::with_draw_target(Some(length), ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(template)
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        bar.set_prefix(self.label.clone());
+        bar
+    }
+
+    fn line(&self, bytes: u64, entries: u64) -> String {
+        let (done, total) = if self.total_bytes > 0 {
+            (bytes, self.total_bytes)
+        } else {
+            (entries, self.total_entries)
+        };
+
+        let elapsed = self.started.elapsed();
+        let eta = if done > 0 && total > done {
+            let remaining = elapsed.as_secs_f64() * (total - done) as f64 / done as f64;
+            format!(", {} left", HumanDuration(Duration::from_secs_f64(remaining)))
+        } else {
+            String::new()
+        };
+
+        if self.total_bytes > 0 {
+            format!(
+                "{}: {}/{}, {}/{} entries{}",
+                self.label,
+                HumanBytes(bytes),
+                HumanBytes(self.total_bytes),
+                entries,
+                self.total_entries,
+                eta
+            )
+        } else {
+            format!("{}: {}/{} entries{}", self.label, entries, self.total_entries, eta)
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.get() {
+            bar.finish_and_clear();
+        } else if self.last_line.lock().unwrap().is_some() {
+            let bytes = self.bytes.load(Ordering::Relaxed);
+            let entries = self.entries.load(Ordering::Relaxed);
+            if self.total_bytes > 0 {
+                eprintln!(
+                    "{}: done, {} and {} entries in {}",
+                    self.label,
+                    HumanBytes(bytes),
+                    entries,
+                    HumanDuration(self.started.elapsed())
+                );
+            } else {
+                eprintln!(
+                    "{}: done, {} entries in {}",
+                    self.label,
+                    entries,
+                    HumanDuration(self.started.elapsed())
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use crate::trm::Args;
+use crate::{copy, utils};
+
+use indicatif::HumanBytes;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+/// Number of files above which -I asks, unless set with --prompt-threshold or in the config file
+pub const DEFAULT_THRESHOLD: usize = 3;
+
+/// Ask on stderr, so that the question is seen even when stdout is redirected, and read the
+/// answer from stdin. Only an answer starting with `y` is a yes
+pub fn confirm(prompt: &str) -> bool {
+    ask(prompt, false)
+}
+
+/// Like `confirm`, reading the answer from the terminal instead when stdin holds the list of files
+fn ask(prompt: &str, from_tty: bool) -> bool {
+    answer(prompt, from_tty).starts_with(['y', 'Y'])
+}
+
+/// Ask on stderr and return the answer, trimmed. An answer which cannot be read is empty
+pub fn answer(prompt: &str, from_tty: bool) -> String {
+    eprint!("{} ", prompt);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    let answered = if from_tty {
+        File::open("/dev/tty").and_then(|tty| BufReader::new(tty).read_line(&mut answer))
+    } else {
+        io::stdin().read_line(&mut answer)
+    };
+    match answered {
+        Ok(_) => answer.trim().to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+/// Type and size of a file as shown in a prompt, e.g. `directory, 12 entries, 3.40 MiB`
+fn describe(path: &Path) -> String {
+    let Ok(meta) = path.symlink_metadata() else {
+        return "missing".to_string();
+    };
+
+    if meta.is_symlink() {
+        return match path.read_link() {
+            Ok(target) => format!("symlink to {}", target.display()),
+            Err(_) => "symlink".to_string(),
+        };
+    }
+    if meta.is_dir() {
+        return match copy::tree_stats(path) {
+            Ok(stats) => format!("directory, {} entries, {}", stats.entries - 1, HumanBytes(stats.bytes)),
+            Err(_) => "directory".to_string(),
+        };
+    }
+    if meta.is_file() {
+        return format!("file, {}", HumanBytes(meta.len()));
+    }
+    "special file".to_string()
+}
+
+/// Ask about the files to be trashed as -i and -I want, and return the ones to trash.
+/// With -i every file is asked about on its own. With -I a single question covers them all,
+/// and nothing is trashed if it is turned down
+pub fn select(args: &Args, files: &[PathBuf]) -> Vec<PathBuf> {
+    let from_tty = utils::list_from_stdin(args);
+    if args.interactive {
+        return files
+            .iter()
+            .filter(|file| ask(&format!("Trash '{}' ({})? [y/N]", file.display(), describe(file)), from_tty))
+            .cloned()
+            .collect();
+    }
+
+    let threshold = args.prompt_threshold.unwrap_or(DEFAULT_THRESHOLD);
+    let dirs = files
+        .iter()
+        .filter(|file| file.symlink_metadata().is_ok_and(|meta| meta.is_dir()))
+        .count();
+    if !args.interactive_once || (files.len() <= threshold && dirs == 0) {
+        return files.to_vec();
+    }
+
+    let bytes: u64 = files
+        .iter()
+        .filter(|file| utils::path_exists(file))
+        .filter_map(|file| copy::tree_stats(file).ok())
+        .map(|stats| stats.bytes)
+        .sum();
+    let dirs_note = match dirs {
+        0 => String::new(),
+        1 => ", 1 of them a directory".to_string(),
+        dirs => format!(", {} of them directories", dirs),
+    };
+    let prompt = format!(
+        "Trash {} file(s){} ({} in total)? [y/N]",
+        files.len(),
+        dirs_note,
+        HumanBytes(bytes)
+    );
+    if !ask(&prompt, from_tty) {
+        eprintln!("Nothing was trashed");
+        exit(0);
+    }
+    files.to_vec()
+}
@@ -0,0 +1,246 @@
+use crate::trm::{get_journal_dir, get_key_file, get_lock_file, get_log_file, get_stores_file, Args};
+use crate::{store, utils};
+
+use clap::ValueEnum;
+use std::{
+    collections::HashSet,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+/// Safety rules checked before anything is trashed. Each one can be lifted with `--allow`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Rule {
+    /// The root directory
+    Root,
+    /// The home directory and the directories above it
+    Home,
+    /// The trash itself, everything in it, and the directories holding it or its log
+    Trash,
+    /// Mount points
+    Mount,
+    /// The current directory and the directories above it
+    Cwd,
+    /// The paths in `protected_paths` of the config file or given with --protect
+    Protected,
+}
+
+impl Rule {
+    fn name(&self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+}
+
+/// How a path to be trashed relates to a guarded path
+fn relation(path: &Path, guarded: &Path) -> Option<&'static str> {
+    if path == guarded {
+        Some("is")
+    } else if guarded.starts_with(path) {
+        Some("contains")
+    } else if path.starts_with(guarded) {
+        Some("is inside")
+    } else {
+        None
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Undo the octal escapes of spaces and other special characters in /proc/self/mountinfo
+fn unescape_mount_point(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut unescaped: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = field.get(i + 1..i + 4).and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match code {
+            Some(code) if bytes[i] == b'\\' => {
+                unescaped.push(code);
+                i += 4;
+            }
+            _ => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&unescaped).into_owned())
+}
+
+/// The mount points listed in /proc/self/mountinfo, if it can be read
+fn mount_points() -> Option<HashSet<PathBuf>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    Some(
+        mountinfo
+            .lines()
+            .filter_map(|line| line.split(' ').nth(4))
+            .map(unescape_mount_point)
+            .collect(),
+    )
+}
+
+fn is_mount_point(path: &Path, mount_points: &Option<HashSet<PathBuf>>) -> bool {
+    if mount_points.as_ref().is_some_and(|mount_points| mount_points.contains(path)) {
+        return true;
+    }
+
+    // without /proc, a directory on another device than its parent is a mount point
+    match (path.symlink_metadata(), path.parent().map(|parent| parent.metadata())) {
+        (Ok(meta), Some(Ok(parent))) => meta.is_dir() && meta.dev() != parent.dev(),
+        _ => false,
+    }
+}
+
+/// Expand a leading `~` of a path from the config file
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => {
+            PathBuf::from(format!("{}{}", home, rest))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+/// The paths guarded by the rules which are not lifted, looked up once for all the files checked
+pub struct Guards {
+    allow: Vec<Rule>,
+    home: Option<PathBuf>,
+    trash: Vec<PathBuf>,
+    mount_points: Option<HashSet<PathBuf>>,
+    cwd: Option<PathBuf>,
+    protected: Vec<PathBuf>,
+}
+
+impl Guards {
+    pub fn new(args: &Args, dir_path: &Path) -> Guards {
+        let allowed = |rule: Rule| args.allow.contains(&rule);
+
+        let home = match std::env::var("HOME") {
+            Ok(home) if !allowed(Rule::Home) => Some(canonical(Path::new(&home))),
+            _ => None,
+        };
+
+        let mut trash = vec![];
+        if !allowed(Rule::Trash) {
+            trash = store::all_stores(dir_path);
+            trash.extend(
+                [get_log_file(), get_stores_file(), get_lock_file(), get_key_file(), get_journal_dir()]
+                    .iter()
+                    .map(PathBuf::from),
+            );
+            trash = trash.iter().map(|trash| canonical(trash)).collect();
+        }
+
+        let mut protected = vec![];
+        if !allowed(Rule::Protected) {
+            protected = args.protected_paths.iter().map(|path| canonical(&expand_home(path))).collect();
+        }
+
+        Guards {
+            allow: args.allow.clone(),
+            home,
+            trash,
+            mount_points: if allowed(Rule::Mount) { None } else { mount_points() },
+            cwd: if allowed(Rule::Cwd) { None } else { std::env::current_dir().ok() },
+            protected,
+        }
+    }
+
+    /// The first rule which forbids trashing `path`, with the reason why
+    fn violation(&self, path: &Path) -> Option<(Rule, String)> {
+        let allowed = |rule: Rule| self.allow.contains(&rule);
+
+        if !allowed(Rule::Root) && path == Path::new("/") {
+            return Some((Rule::Root, "it is the root directory".to_string()));
+        }
+
+        if let Some(home) = &self.home {
+            match relation(path, home) {
+                Some("is") => return Some((Rule::Home, "it is your home directory".to_string())),
+                Some("contains") => {
+                    return Some((Rule::Home, format!("it contains your home directory {}", home.display())))
+                }
+                _ => {}
+            }
+        }
+
+        for trash in &self.trash {
+            let reason = match relation(path, trash) {
+                Some("is") => "it is part of the trash".to_string(),
+                Some("contains") => format!("it contains {}, which is part of the trash", trash.display()),
+                Some(_) => format!("it is inside the trash {}", trash.display()),
+                None => continue,
+            };
+            return Some((Rule::Trash, reason));
+        }
+
+        if !allowed(Rule::Mount) && is_mount_point(path, &self.mount_points) {
+            return Some((Rule::Mount, "it is a mount point".to_string()));
+        }
+
+        if let Some(cwd) = &self.cwd {
+            match relation(path, cwd) {
+                Some("is") => return Some((Rule::Cwd, "it is the current directory".to_string())),
+                Some("contains") => {
+                    return Some((Rule::Cwd, format!("it contains the current directory {}", cwd.display())))
+                }
+                _ => {}
+            }
+        }
+
+        for protected in &self.protected {
+            if let Some(relation) = relation(path, protected) {
+                return Some((
+                    Rule::Protected,
+                    format!("it {} the protected path {}", relation, protected.display()),
+                ));
+            }
+        }
+
+        None
+    }
+
+    /// Check one file against the safety rules. A file which does not exist passes
+    pub fn check(&self, file: &Path) -> Result<(), String> {
+        let Ok(full_path) = utils::absolute_path(file) else {
+            return Ok(());
+        };
+        match self.violation(&full_path) {
+            Some((rule, reason)) => Err(format!(
+                "Refusing to trash {}: {}. Use --allow={} to trash it anyway",
+                full_path.display(),
+                reason,
+                rule.name()
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Check every file against the safety rules before any of them is trashed.
+/// Files which do not exist are left for `move_files` to report
+pub fn check(args: &Args, dir_path: &Path, files: &[PathBuf]) -> Result<(), String> {
+    let guards = Guards::new(args, dir_path);
+    files.iter().try_for_each(|file| guards.check(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_mount_points() {
+        assert_eq!(unescape_mount_point("/mnt/data"), PathBuf::from("/mnt/data"));
+        assert_eq!(unescape_mount_point("/mnt/my\\040disk"), PathBuf::from("/mnt/my disk"));
+        assert_eq!(unescape_mount_point("/a\\011b\\134c"), PathBuf::from("/a\tb\\c"));
+    }
+
+    #[test]
+    fn keep_invalid_escapes() {
+        assert_eq!(unescape_mount_point("/mnt/x\\9"), PathBuf::from("/mnt/x\\9"));
+        assert_eq!(unescape_mount_point("/mnt/x\\"), PathBuf::from("/mnt/x\\"));
+    }
+}
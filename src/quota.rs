@@ -0,0 +1,326 @@
+use crate::logging::{owners, read_logs, write_logs, FileInfo, Filter, OpType};
+use crate::plan::{Kind, Plan};
+use crate::progress::Progress;
+use crate::trm::{get_log_file, Args};
+use crate::{archive, copy, crypt, dedup, store, utils};
+
+use chrono::{DateTime, Local};
+use indicatif::HumanBytes;
+use std::{
+    collections::{BTreeSet, HashMap},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+/// Size of every store and how much space is available to it (its own size plus the free space)
+struct StoreUsage {
+    store: PathBuf,
+    used: u64,
+    capacity: u64,
+}
+
+impl StoreUsage {
+    fn over_percent(&self, max_percent: Option<f64>) -> bool {
+        match max_percent {
+            Some(max_percent) => self.used as f64 > self.capacity as f64 * max_percent / 100.0,
+            None => false,
+        }
+    }
+}
+
+fn quota_set(args: &Args) -> bool {
+    args.max_size.is_some() || args.max_percent.is_some()
+}
+
+/// Refuse to trash an item which alone is bigger than the quota, as no amount of
+/// eviction would make room for it. `size` is the space the item takes up on disk
+pub fn check_item(args: &Args, dir_path: &Path, full_path: &Path, size: u64) -> Result<(), String> {
+    if !quota_set(args) {
+        return Ok(());
+    }
+
+    if let Some(max_size) = args.max_size {
+        if size > max_size {
+            return Err(format!(
+                "{} ({}) is bigger than the maximum trash size of {}",
+                full_path.display(),
+                HumanBytes(size),
+                HumanBytes(max_size)
+            ));
+        }
+    }
+
+    if let Some(max_percent) = args.max_percent {
+        let store = store::locate(full_path, dir_path);
+        let logs = match Path::new(&get_log_file()).exists() {
+            true => read_logs(Filter::All),
+            false => vec![],
+        };
+        let trash_size = store_sizes(std::slice::from_ref(&store), &logs)[0];
+        // a store which is not created yet has the space of the filesystem it would be on
+        let free = copy::available_space(&store)
+            .or_else(|_| copy::available_space(store.parent().unwrap_or(&store)))
+            .unwrap_or(0);
+        let capacity = trash_size + free;
+        if size as f64 > capacity as f64 * max_percent / 100.0 {
+            return Err(format!(
+                "{} ({}) is bigger than {}% of the space available to the trash in {}",
+                full_path.display(),
+                HumanBytes(size),
+                max_percent,
+                store.display()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The items of the log entry at `index` which take up space in the trash, as the index of
+/// their store in `stores` and their size on disk
+fn entry_items(stores: &[PathBuf], owner: &HashMap<&String, usize>, logs: &[FileInfo], index: usize) -> Vec<(usize, u64)> {
+    let log = &logs[index];
+    let store_index = |path: &Path| {
+        let store = store::store_of(path, stores)?;
+        stores.iter().position(|s| s == store)
+    };
+
+    let mut items: Vec<(usize, u64)> = log
+        .dst
+        .iter()
+        .enumerate()
+        .filter(|(_, dst)| owner.get(dst) == Some(&index))
+        .map(|(i, dst)| (i, PathBuf::from(dst)))
+        .filter(|(_, dst)| utils::path_exists(dst))
+        .filter_map(|(i, dst)| {
+            // the size recorded when trashing saves walking the tree again
+            let size = match log.meta.get(i) {
+                Some(meta) => meta.disk_usage,
+                None => copy::tree_stats(&dst).map(|stats| stats.disk_usage).unwrap_or(0),
+            };
+            Some((store_index(&dst)?, size))
+        })
+        .collect();
+
+    // compressed items take up the space of their archives
+    let archives: BTreeSet<&String> = log.archived.values().collect();
+    for archive in archives {
+        let archive = Path::new(archive);
+        if let Some(i) = store_index(archive) {
+            items.push((i, archive.metadata().map(|meta| meta.blocks() * 512).unwrap_or(0)));
+        }
+    }
+
+    items
+}
+
+/// Space taken up in every store by the items the log says are in it. Summing what was recorded
+/// is much quicker than walking the stores, and does not depend on every entry being readable
+fn store_sizes(stores: &[PathBuf], logs: &[FileInfo]) -> Vec<u64> {
+    let owner = owners(logs);
+    let mut sizes = vec![0; stores.len()];
+    for (index, log) in logs.iter().enumerate() {
+        if log.operation != OpType::TRASH {
+            continue;
+        }
+        for (i, size) in entry_items(stores, &owner, logs, index) {
+            sizes[i] += size;
+        }
+    }
+    sizes
+}
+
+fn store_usage(stores: &[PathBuf], logs: &[FileInfo]) -> Vec<StoreUsage> {
+    stores
+        .iter()
+        .zip(store_sizes(stores, logs))
+        .map(|(store, used)| StoreUsage {
+            store: store.clone(),
+            used,
+            capacity: used + copy::available_space(store).unwrap_or(0),
+        })
+        .collect()
+}
+
+fn over_quota(args: &Args, usage: &[StoreUsage]) -> bool {
+    let total: u64 = usage.iter().map(|u| u.used).sum();
+    args.max_size.is_some_and(|max_size| total > max_size) || usage.iter().any(|u| u.over_percent(args.max_percent))
+}
+
+/// Pick the oldest log entries to evict until the trash is under its quota, updating `usage`.
+/// The entry written at `keep_time`, the one just trashed, is never picked
+fn select_evictions(
+    args: &Args,
+    stores: &[PathBuf],
+    usage: &mut [StoreUsage],
+    owner: &HashMap<&String, usize>,
+    logs: &[FileInfo],
+    keep_time: Option<DateTime<Local>>,
+) -> Vec<usize> {
+    let mut evicted: Vec<usize> = vec![];
+
+    // the log is in chronological order, so the oldest entries come first
+    for (index, log) in logs.iter().enumerate() {
+        if !over_quota(args, usage) {
+            break;
+        }
+        if log.operation != OpType::TRASH || Some(log.moved_time) == keep_time {
+            continue;
+        }
+
+        let items = entry_items(stores, owner, logs, index);
+
+        // when only the percentage is exceeded, evicting from another filesystem does not help
+        let total: u64 = usage.iter().map(|u| u.used).sum();
+        let total_over = args.max_size.is_some_and(|max_size| total > max_size);
+        let helps = total_over || items.iter().any(|(i, _)| usage[*i].over_percent(args.max_percent));
+        if !helps {
+            continue;
+        }
+
+        for (i, size) in items {
+            usage[i].used = usage[i].used.saturating_sub(size);
+        }
+        evicted.push(index);
+    }
+
+    evicted
+}
+
+/// Bring the trash back under its quota by permanently deleting the oldest trashed items.
+/// The entry written at `keep_time`, the one just trashed, is never evicted
+pub fn enforce(args: &Args, dir_path: &Path, keep_time: DateTime<Local>) {
+    if !quota_set(args) || !Path::new(&get_log_file()).exists() {
+        return;
+    }
+
+    let stores = store::all_stores(dir_path);
+    let logs = read_logs(Filter::All);
+    let mut usage = store_usage(&stores, &logs);
+    if !over_quota(args, &usage) {
+        return;
+    }
+
+    let owner = owners(&logs);
+    let evicted = select_evictions(args, &stores, &mut usage, &owner, &logs, Some(keep_time));
+
+    if evicted.is_empty() {
+        eprintln!("Trash is over its size limit, but there is nothing older to delete");
+        return;
+    }
+
+    evict(args, &stores, &owner, &logs, &evicted);
+
+    if over_quota(args, &usage) {
+        eprintln!("Trash is still over its size limit");
+    }
+}
+
+/// Add the items `enforce` would delete once the `added` items, each a store and the space
+/// it would take up there, are in the trash
+pub fn plan(args: &Args, dir_path: &Path, added: &[(PathBuf, u64)], plan: &mut Plan) {
+    if !quota_set(args) || !Path::new(&get_log_file()).exists() {
+        return;
+    }
+
+    let mut stores = store::all_stores(dir_path);
+    for (store, _) in added {
+        if !stores.contains(store) {
+            stores.push(store.clone());
+        }
+    }
+    let logs = read_logs(Filter::All);
+    let mut usage = store_usage(&stores, &logs);
+    for (store, size) in added {
+        if let Some(u) = usage.iter_mut().find(|u| &u.store == store) {
+            // the item counts towards the trash once it is moved in
+            u.used += size;
+        }
+    }
+    if !over_quota(args, &usage) {
+        return;
+    }
+
+    let owner = owners(&logs);
+    for index in select_evictions(args, &stores, &mut usage, &owner, &logs, None) {
+        let log = &logs[index];
+        let trashed = format!("trashed {}, over the size limit", log.moved_time.to_rfc2822());
+        for dst in &log.dst {
+            let compressed = log.archived.contains_key(dst);
+            if !compressed && (owner.get(dst) != Some(&index) || !utils::path_exists(Path::new(dst))) {
+                continue;
+            }
+            let mut notes = vec![trashed.clone()];
+            if compressed {
+                notes.push("compressed".to_string());
+            }
+            plan.add(Kind::Delete, Path::new(dst), None, notes);
+        }
+    }
+}
+
+/// Delete the items of the given log entries from disk and drop the entries from the log
+fn evict(
+    args: &Args,
+    stores: &[PathBuf],
+    owner: &HashMap<&String, usize>,
+    logs: &[FileInfo],
+    evicted: &[usize],
+) {
+    eprintln!("Trash is over its size limit, permanently deleted the oldest items:");
+
+    let mut new_logs: Vec<&FileInfo> = Vec::with_capacity(logs.len());
+
+    for (index, log) in logs.iter().enumerate() {
+        if !evicted.contains(&index) {
+            new_logs.push(log);
+            continue;
+        }
+
+        for (src, dst) in log.src.iter().zip(&log.dst) {
+            let src = &crypt::reveal_path(src, None);
+            if log.archived.contains_key(dst) {
+                eprintln!("  {} (trashed {}, compressed)", src, log.moved_time.to_rfc2822());
+                continue;
+            }
+            if owner.get(dst) != Some(&index) {
+                continue;
+            }
+            let dst = PathBuf::from(dst);
+            if !utils::path_exists(&dst) {
+                continue;
+            }
+
+            let stats = copy::tree_stats(&dst).ok();
+            let progress = Progress::new(
+                &format!("Deleting {}", src),
+                stats.as_ref().map_or(0, |stats| stats.bytes),
+                stats.as_ref().map_or(0, |stats| stats.entries),
+            );
+            match copy::remove_tree(&dst, &progress) {
+                Ok(_) => {
+                    eprintln!("  {} (trashed {})", src, log.moved_time.to_rfc2822());
+                    if let Some(store) = store::store_of(&dst, stores) {
+                        utils::remove_empty_parents(&dst, store);
+                        dedup::forget(store, &dst);
+                    }
+                }
+                Err(e) => eprintln!("  Failed to delete {}: {}", dst.display(), e),
+            }
+        }
+
+        archive::remove_archives(log);
+
+        if args.verbose {
+            println!("Evicted entry from {}", log.moved_time.to_rfc2822());
+        }
+    }
+
+    for store in stores {
+        dedup::collect_garbage(store);
+    }
+
+    if let Err(e) = write_logs(&new_logs) {
+        eprintln!("Failed to write logs: {}", e);
+    }
+}
@@ -0,0 +1,371 @@
+use crate::trm::{get_default_dir, move_files, Args};
+use crate::{prompt, protect, utils};
+
+use clap::{Parser, ValueEnum};
+use std::{
+    ffi::{CString, OsString},
+    io::{self, IsTerminal},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+/// Command line of GNU rm. Files are trashed instead of deleted
+#[derive(Parser, Debug)]
+#[command(
+    name = "rm",
+    version,
+    about = "Remove (trash) the FILE(s), accepting the options of GNU rm. Trashed files can be restored with `trm -u`"
+)]
+pub struct RmArgs {
+    /// Ignore nonexistent files and arguments, never prompt
+    #[arg(short, long, overrides_with_all = ["prompt_always", "prompt_once", "interactive"])]
+    pub force: bool,
+
+    /// Prompt before every removal
+    #[arg(short = 'i', overrides_with_all = ["force", "prompt_once", "interactive"])]
+    pub prompt_always: bool,
+
+    /// Prompt once before removing more than three files, or when removing recursively
+    #[arg(short = 'I', overrides_with_all = ["force", "prompt_always", "interactive"])]
+    pub prompt_once: bool,
+
+    /// Prompt according to WHEN: never, once (-I), or always (-i). Without WHEN, prompt always
+    #[arg(
+        long,
+        value_name = "WHEN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "always",
+        overrides_with_all = ["force", "prompt_always", "prompt_once"]
+    )]
+    pub interactive: Option<When>,
+
+    /// Refuse to trash a directory which has another filesystem mounted inside it
+    #[arg(long)]
+    pub one_file_system: bool,
+
+    /// Do not treat '/' specially
+    #[arg(long, overrides_with = "preserve_root")]
+    pub no_preserve_root: bool,
+
+    /// Do not remove '/' (default). With 'all', reject any argument on a separate device from its parent
+    #[arg(long, value_enum, value_name = "all", num_args = 0..=1, require_equals = true)]
+    pub preserve_root: Option<Option<PreserveRoot>>,
+
+    /// Remove directories and their contents recursively
+    #[arg(short = 'r', short_alias = 'R', long)]
+    pub recursive: bool,
+
+    /// Remove empty directories
+    #[arg(short = 'd', long = "dir")]
+    pub empty_dirs: bool,
+
+    /// Explain what is being done
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Safety rules of trm to lift, as with `trm --allow`
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub allow: Vec<protect::Rule>,
+
+    /// Files to remove. Use `--` before names starting with `-`
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum When {
+    #[value(alias = "no", alias = "none")]
+    Never,
+    Once,
+    #[value(alias = "yes")]
+    Always,
+}
+
+/// The only value `--preserve-root` takes
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PreserveRoot {
+    All,
+}
+
+/// Flags which pick the mode for one run, given as the first argument
+const RM_FLAG: &str = "--rm";
+pub const NO_RM_FLAG: &str = "--no-rm";
+
+/// Whether `trm` should behave like GNU rm: when invoked as `rm`, when the first argument is
+/// `--rm`, or when `rm_compat` is set in the config file and the first argument is not `--no-rm`
+pub fn selected(config_rm_compat: bool) -> bool {
+    let mut argv = std::env::args_os();
+    let invoked_as_rm = argv
+        .next()
+        .is_some_and(|arg0| Path::new(&arg0).file_name().is_some_and(|name| name == "rm"));
+    match argv.next() {
+        Some(arg) if arg == RM_FLAG => true,
+        Some(arg) if arg == NO_RM_FLAG => false,
+        _ => invoked_as_rm || config_rm_compat,
+    }
+}
+
+/// The command line without the mode flag
+pub fn argv() -> Vec<OsString> {
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    if argv.get(1).is_some_and(|arg| arg == RM_FLAG || arg == NO_RM_FLAG) {
+        argv.remove(1);
+    }
+    argv
+}
+
+impl RmArgs {
+    /// The `trm` arguments the files are trashed with. Everything else comes from the config file
+    pub fn to_args(&self) -> Args {
+        let mut allow = self.allow.clone();
+        if self.no_preserve_root {
+            allow.push(protect::Rule::Root);
+        }
+        Args {
+            dir: get_default_dir(),
+            allow,
+            keep_going: true,
+            ..Default::default()
+        }
+    }
+
+    /// When to prompt, if asked for. The last of -f, -i, -I and --interactive wins
+    fn when(&self) -> Option<When> {
+        if self.force {
+            Some(When::Never)
+        } else if self.prompt_always {
+            Some(When::Always)
+        } else if self.prompt_once {
+            Some(When::Once)
+        } else {
+            self.interactive
+        }
+    }
+}
+
+fn program_name() -> String {
+    std::env::args_os()
+        .next()
+        .and_then(|arg0| Path::new(&arg0).file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "rm".to_string())
+}
+
+/// Ask the way rm does, with its name in front
+fn confirm(prompt: &str) -> bool {
+    prompt::confirm(&format!("{}: {}", program_name(), prompt))
+}
+
+/// How rm names a file in its prompts
+fn describe(path: &Path, meta: &std::fs::Metadata) -> &'static str {
+    let file_type = meta.file_type();
+    if file_type.is_symlink() {
+        "symbolic link"
+    } else if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_file() && meta.len() == 0 {
+        "regular empty file"
+    } else if file_type.is_file() {
+        "regular file"
+    } else if path.exists() {
+        "special file"
+    } else {
+        "file"
+    }
+}
+
+/// Whether the last component of a path as given is `.` or `..`, which `Path` would normalize away
+fn is_dot_or_dotdot(path: &Path) -> bool {
+    let bytes = path.as_os_str().as_bytes();
+    let trimmed = match bytes.iter().rposition(|byte| *byte != b'/') {
+        Some(end) => &bytes[..=end],
+        None => return false,
+    };
+    let name = trimmed.rsplit(|byte| *byte == b'/').next().unwrap_or(trimmed);
+    name == b"." || name == b".."
+}
+
+/// An error as rm shows it, without the error number
+fn error_message(e: &io::Error) -> String {
+    let message = e.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+fn is_writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => true,
+    }
+}
+
+/// The first directory below `path` which is on another filesystem
+fn other_filesystem(path: &Path, dev: u64) -> Option<PathBuf> {
+    walkdir::WalkDir::new(path)
+        .follow_root_links(false)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.file_type().is_dir() && entry.metadata().is_ok_and(|meta| meta.dev() != dev))
+        .map(|entry| entry.into_path())
+}
+
+/// Check one operand the way rm does. Returns whether it should be trashed,
+/// or the message to report when it cannot be
+fn check_operand(rm_args: &RmArgs, guards: &protect::Guards, file: &Path) -> Result<bool, String> {
+    let quoted = format!("'{}'", file.display());
+
+    let is_root = utils::absolute_path(file).is_ok_and(|path| path == Path::new("/"));
+    if is_root && !rm_args.no_preserve_root {
+        return Err(format!(
+            "it is dangerous to operate recursively on '/'\n{}: use --no-preserve-root to override this failsafe",
+            program_name()
+        ));
+    }
+    if !is_root && is_dot_or_dotdot(file) {
+        return Err(format!("refusing to remove '.' or '..' directory: skipping {}", quoted));
+    }
+
+    let meta = match file.symlink_metadata() {
+        Ok(meta) => meta,
+        Err(_) if rm_args.force => return Ok(false),
+        Err(e) => return Err(format!("cannot remove {}: {}", quoted, error_message(&e))),
+    };
+
+    if meta.is_dir() && !rm_args.recursive {
+        let empty = std::fs::read_dir(file).is_ok_and(|mut entries| entries.next().is_none());
+        if !rm_args.empty_dirs {
+            return Err(format!("cannot remove {}: Is a directory", quoted));
+        }
+        if !empty {
+            return Err(format!("cannot remove {}: Directory not empty", quoted));
+        }
+    }
+
+    if rm_args.preserve_root == Some(Some(PreserveRoot::All)) {
+        let parent_dev = utils::absolute_path(file)
+            .ok()
+            .and_then(|path| path.parent().and_then(|parent| parent.metadata().ok()))
+            .map(|parent| parent.dev());
+        if meta.is_dir() && parent_dev.is_some_and(|dev| dev != meta.dev()) {
+            return Err(format!(
+                "skipping {}, since it's on a different device\n{}: and --preserve-root=all is in effect",
+                quoted,
+                program_name()
+            ));
+        }
+    }
+
+    if rm_args.one_file_system && meta.is_dir() {
+        if let Some(mounted) = other_filesystem(file, meta.dev()) {
+            return Err(format!(
+                "skipping {}, since '{}' is on a different device",
+                quoted,
+                mounted.display()
+            ));
+        }
+    }
+
+    guards.check(file)?;
+
+    let write_protected = !meta.file_type().is_symlink() && !is_writable(file);
+    let ask = match rm_args.when() {
+        Some(When::Always) => true,
+        Some(When::Never) => false,
+        // like rm, a write-protected file is only asked about when someone can answer
+        None | Some(When::Once) => write_protected && io::stdin().is_terminal(),
+    };
+    if ask {
+        let protection = if write_protected { "write-protected " } else { "" };
+        return Ok(confirm(&format!("remove {}{} {}?", protection, describe(file, &meta), quoted)));
+    }
+
+    Ok(true)
+}
+
+/// Trash the operands with the semantics of GNU rm: a missing operand is an error unless
+/// forced, every operand is handled on its own, and the exit status is 1 if any of them failed
+pub fn remove(rm_args: &RmArgs, args: &Args, dir_path: &PathBuf) {
+    if rm_args.files.is_empty() {
+        if rm_args.force {
+            exit(0);
+        }
+        eprintln!("{}: missing operand", program_name());
+        eprintln!("Try '{} --help' for more information.", program_name());
+        exit(1);
+    }
+
+    if rm_args.when() == Some(When::Once) && (rm_args.files.len() > 3 || rm_args.recursive) {
+        let count = rm_args.files.len();
+        let prompt = format!(
+            "remove {} argument{}{}?",
+            count,
+            if count == 1 { "" } else { "s" },
+            if rm_args.recursive { " recursively" } else { "" }
+        );
+        if !confirm(&prompt) {
+            exit(0);
+        }
+    }
+
+    let guards = protect::Guards::new(args, dir_path);
+    let mut failed = false;
+    let mut files: Vec<PathBuf> = vec![];
+    for file in &rm_args.files {
+        match check_operand(rm_args, &guards, file) {
+            Ok(true) => files.push(file.clone()),
+            Ok(false) => {}
+            Err(message) => {
+                eprintln!("{}: {}", program_name(), message);
+                failed = true;
+            }
+        }
+    }
+
+    let dirs: Vec<bool> = files
+        .iter()
+        .map(|file| file.symlink_metadata().is_ok_and(|meta| meta.is_dir()))
+        .collect();
+
+    let mut not_trashed: Vec<PathBuf> = vec![];
+    if !files.is_empty() {
+        // each file which cannot be trashed is reported, and the others are trashed anyway
+        not_trashed = move_files(args, dir_path, &files, None);
+        failed |= !not_trashed.is_empty();
+    }
+
+    if rm_args.verbose {
+        for (file, is_dir) in files.iter().zip(dirs) {
+            if not_trashed.contains(file) {
+                continue;
+            }
+            if is_dir {
+                println!("removed directory '{}'", file.display());
+            } else {
+                println!("removed '{}'", file.display());
+            }
+        }
+    }
+
+    exit(if failed { 1 } else { 0 });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_and_dotdot() {
+        for path in [".", "..", "./", "../", "dir/.", "dir/..", "/a/b/..//"] {
+            assert!(is_dot_or_dotdot(Path::new(path)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn not_dot_or_dotdot() {
+        for path in ["/", "file", ".hidden", "..name", "dir/./file", "../file", "..."] {
+            assert!(!is_dot_or_dotdot(Path::new(path)), "{}", path);
+        }
+    }
+}
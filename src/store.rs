@@ -0,0 +1,129 @@
+use crate::trm::get_stores_file;
+use crate::utils;
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{Error, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+/// Name of the trash root created at the top of every other filesystem, e.g. `/mnt/data/.trm-1000`
+pub fn store_name() -> String {
+    format!(".trm-{}", unsafe { libc::getuid() })
+}
+
+/// Walk up from `path` to the top-most directory which is still on the device `dev`
+fn mount_point(path: &Path, dev: u64) -> PathBuf {
+    let mut curr = path.to_path_buf();
+    while let Some(parent) = curr.parent() {
+        match parent.metadata() {
+            Ok(meta) if meta.dev() == dev => curr = parent.to_path_buf(),
+            _ => break,
+        }
+    }
+    curr
+}
+
+/// The trash root an absolute path goes to, without creating it.
+///
+/// Files on the same filesystem as the main trash directory go there. For any other filesystem
+/// a store at its mount point is used, so trashing stays a rename instead of a copy
+pub fn locate(path: &Path, home: &Path) -> PathBuf {
+    // a path is renamed within the filesystem of its parent directory
+    let parent = path.parent().unwrap_or(path);
+    let dev = match parent.metadata() {
+        Ok(meta) => meta.dev(),
+        Err(_) => return home.to_path_buf(),
+    };
+
+    // the main trash directory may not be created yet
+    let home_dev = home.ancestors().find_map(|dir| dir.metadata().ok()).map(|meta| meta.dev());
+    if home_dev == Some(dev) {
+        return home.to_path_buf();
+    }
+
+    // anyone who can write at the top of the filesystem may have put something there first
+    let store = mount_point(parent, dev).join(store_name());
+    match store.symlink_metadata() {
+        Ok(meta) if !is_own_dir(&meta) => home.to_path_buf(),
+        _ => store,
+    }
+}
+
+fn is_own_dir(meta: &fs::Metadata) -> bool {
+    meta.is_dir() && utils::owned_by_user(meta)
+}
+
+/// Pick the trash root for an absolute path, creating it if needed. If the store on another
+/// filesystem cannot be created, the main trash directory is used and the file is copied over
+pub fn store_for(path: &Path, home: &Path) -> PathBuf {
+    let store = locate(path, home);
+    if store == home {
+        return store;
+    }
+
+    if let Err(e) = utils::create_private_dir(&store) {
+        eprintln!("Not using trash store {}: {}", store.display(), e);
+        return home.to_path_buf();
+    }
+
+    if let Err(e) = register(&store) {
+        eprintln!("Failed to register trash store {}: {}", store.display(), e);
+        return home.to_path_buf();
+    }
+
+    store
+}
+
+/// Remember a store, so that listing and recovering all files can find it again
+fn register(store: &Path) -> Result<(), Error> {
+    let store_name = store.display().to_string();
+    if let Ok(contents) = fs::read_to_string(get_stores_file()) {
+        if contents.lines().any(|line| line == store_name) {
+            return Ok(());
+        }
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_stores_file())?;
+    writeln!(file, "{}", store_name)
+}
+
+/// The main trash directory followed by every other store which still exists
+pub fn all_stores(home: &Path) -> Vec<PathBuf> {
+    let mut stores = vec![home.to_path_buf()];
+
+    if let Ok(contents) = fs::read_to_string(get_stores_file()) {
+        for line in contents.lines() {
+            let store = PathBuf::from(line);
+            let own_dir = store.symlink_metadata().is_ok_and(|meta| is_own_dir(&meta));
+            if own_dir && !stores.contains(&store) {
+                stores.push(store);
+            }
+        }
+    }
+
+    stores
+}
+
+/// The store a path in the trash belongs to
+pub fn store_of<'a>(trash_path: &Path, stores: &'a [PathBuf]) -> Option<&'a PathBuf> {
+    stores
+        .iter()
+        .filter(|store| trash_path.starts_with(store))
+        .max_by_key(|store| store.components().count())
+}
+
+/// Where an original path would be found in the trash. Falls back to the main trash
+/// directory when no store holds it
+pub fn trash_path(full_path: &Path, stores: &[PathBuf]) -> PathBuf {
+    let relative = full_path.strip_prefix("/").unwrap_or(full_path);
+    stores
+        .iter()
+        .map(|store| store.join(relative))
+        .find(|path| path.symlink_metadata().is_ok())
+        .unwrap_or_else(|| stores[0].join(relative))
+}
@@ -1,10 +1,20 @@
-use crate::logging::{append_to_logs, FileInfo, OpType};
-use chrono::Local;
+use crate::conflict::{self, OnConflict, Resolution};
+use crate::journal::Journal;
+use crate::plan::{Kind, Plan};
+use crate::progress::Progress;
+use crate::logging::{
+    append_to_logs, archived_items, encrypted_items, find_operation, owners, read_logs, trashed_items, trashed_originals, trashed_since,
+    FileInfo, Filter, ItemMeta, OpType, TrashedItem,
+};
+use chrono::{DateTime, Local};
 use clap::{Parser, Subcommand};
+use comfy_table::{modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL, Table};
+use indicatif::HumanBytes;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::{io::Error, path::PathBuf};
+use std::{io::Error, path::{Path, PathBuf}};
 
-use crate::utils;
+use crate::{archive, copy, crypt, dedup, prompt, protect, quota, store, utils};
 
 
 pub fn get_default_dir() -> String {
@@ -17,6 +27,34 @@ pub fn get_log_file() -> String {
     format!("/tmp/trm-{}.log", user)
 }
 
+pub fn get_stores_file() -> String {
+    let user =std::env::var("USER").unwrap_or("default".to_string());
+    format!("/tmp/trm-{}.stores", user)
+}
+
+pub fn get_lock_file() -> String {
+    let user =std::env::var("USER").unwrap_or("default".to_string());
+    format!("/tmp/trm-{}.lock", user)
+}
+
+pub fn get_key_file() -> String {
+    let user =std::env::var("USER").unwrap_or("default".to_string());
+    format!("/tmp/trm-{}.key", user)
+}
+
+pub fn get_config_file() -> PathBuf {
+    let config_dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME").unwrap_or("/".to_string())).join(".config"),
+    };
+    config_dir.join("trm").join("config.json")
+}
+
+pub fn get_journal_dir() -> String {
+    let user =std::env::var("USER").unwrap_or("default".to_string());
+    format!("/tmp/trm-{}.journal", user)
+}
+
 
 
 #[derive(Parser, Debug, Default)]
@@ -57,6 +95,98 @@ pub struct Args {
     #[arg(short, long, default_value_t = get_default_dir())]
     pub dir: String,
 
+    /// Number of threads used to copy, verify and delete directory trees. 0 uses one per CPU
+    #[arg(short, long, default_value_t = 0)]
+    pub jobs: usize,
+
+    /// Record a checksum of every trashed item, which is verified when it is restored
+    #[arg(long)]
+    pub hash: bool,
+
+    /// Store trashed files by their contents, so identical files take up space only once
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Encrypt trashed items with a key derived from a passphrase (TRM_PASSPHRASE, or asked for) or from --key-file
+    #[arg(long)]
+    pub encrypt: bool,
+
+    /// Also encrypt the original paths of trashed items in the log. Implies --encrypt
+    #[arg(long)]
+    pub encrypt_paths: bool,
+
+    /// File whose contents are used as the encryption key instead of a passphrase
+    #[arg(long)]
+    pub key_file: Option<String>,
+
+    /// Read more files from stdin, one per line. They are all trashed (or restored) as one operation
+    #[arg(long)]
+    pub stdin: bool,
+
+    /// Read more files from FILE, one per line, or from stdin if FILE is `-`
+    #[arg(long, value_name = "FILE")]
+    pub from_file: Option<String>,
+
+    /// Files read with --stdin or --from-file are separated by NUL instead of newlines, as `find -print0` writes them. Implies --stdin without --from-file
+    #[arg(short = '0', long)]
+    pub null: bool,
+
+    /// Ask before trashing each file, showing its type and size
+    #[arg(short = 'i', long)]
+    pub interactive: bool,
+
+    /// Ask once before trashing more files than --prompt-threshold, or any directory
+    #[arg(short = 'I', long)]
+    pub interactive_once: bool,
+
+    /// Number of files above which -I asks [default: 3]
+    #[arg(long)]
+    pub prompt_threshold: Option<usize>,
+
+    /// Safety rules to lift, e.g. `--allow=cwd`. Nothing is trashed if any other rule forbids it
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub allow: Vec<protect::Rule>,
+
+    /// Path which may not be trashed, nor anything in it or above it. Can be given more than once
+    #[arg(long = "protect", value_name = "PATH")]
+    pub protected_paths: Vec<String>,
+
+    /// Restore into DIR instead of where the files were trashed from. DIR is created if needed
+    #[arg(long, global = true, value_name = "DIR")]
+    pub to: Option<String>,
+
+    /// Restore a single file under NAME instead of its original name
+    #[arg(long = "as", global = true, value_name = "NAME")]
+    pub as_name: Option<String>,
+
+    /// What to do when a file is restored to a path which is taken [default: rename]
+    #[arg(long, global = true, value_enum, value_name = "POLICY")]
+    pub on_conflict: Option<OnConflict>,
+
+    /// Show what would be trashed, restored or deleted, without changing anything
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Print what --dry-run would do as JSON
+    #[arg(long, global = true, requires = "dry_run")]
+    pub json: bool,
+
+    /// Age after which trashed items are compressed in the background. Only set from the config file
+    #[arg(skip)]
+    pub compress_after: Option<std::time::Duration>,
+
+    /// Trash the files which can be when others fail, instead of none of them. Only set in rm mode
+    #[arg(skip)]
+    pub keep_going: bool,
+
+    /// Maximum size of the trash, e.g. `10G`. The oldest trashed items are deleted to stay under it
+    #[arg(long, value_parser = utils::parse_size)]
+    pub max_size: Option<u64>,
+
+    /// Maximum percentage of the space available to the trash (its own size plus the free space) that it may use
+    #[arg(long)]
+    pub max_percent: Option<f64>,
+
 }
 
 #[derive(Subcommand, Debug)]
@@ -106,6 +236,60 @@ Example value could be `1hour 12min 5s`
         /// Purge all files in trash
         #[arg(short, long)]
         all: bool
+    },
+
+    /// Restore the files of one operation, wherever they were trashed from, or of the latest ones
+    Undo {
+        /// ID of the operation as shown by history. A unique prefix is enough
+        #[arg(required_unless_present_any = ["last", "since"], conflicts_with_all = ["last", "since"])]
+        id: Option<String>,
+
+        /// Undo the last N trash operations which still have files in the trash
+        #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "1")]
+        last: Option<usize>,
+
+        /// Undo the trash operations done within the given time, e.g. `10m`. Follows same semantics as `before` in history
+        #[arg(long, value_parser = humantime::parse_duration)]
+        since: Option<std::time::Duration>,
+    },
+
+    /// Trash again the files brought back by a restore or undo
+    Redo {
+        /// ID of the restore as shown by history. Defaults to the latest restore which was not redone yet
+        id: Option<String>,
+    },
+
+    /// List the versions of a path which was trashed more than once, or restore one of them
+    Versions {
+        /// Original path of the file
+        path: String,
+
+        /// Restore version N as listed, to the original path. Without N, the latest version
+        #[arg(long, value_name = "N", num_args = 0..=1)]
+        restore: Option<Option<usize>>,
+    },
+
+    /// Bring back everything which was under a directory at a given time and was trashed since
+    Restore {
+        /// The moment to go back to, e.g. `2026-10-01 14:00`, `2026-10-01` or a time ago like `2h`
+        #[arg(long, value_parser = utils::parse_time)]
+        at: DateTime<Local>,
+
+        /// Directory to restore. It may have been trashed itself
+        dir: String,
+    },
+
+    /// Show the files of one operation and where they are now
+    Show {
+        /// ID of the operation as shown by history. A unique prefix is enough
+        id: String,
+    },
+
+    /// Compress trashed items older than the given age. Follows same semantics as `before` in history
+    Compress {
+        /// Compress items trashed before current time - given time. Defaults to `compress_after` in the config file
+        #[arg(long, value_parser = humantime::parse_duration)]
+        after: Option<std::time::Duration>,
     }
 }
 
@@ -125,6 +309,40 @@ impl Args{
             return Err("Files must be provided".to_string());
         }
 
+        if (self.stdin || self.from_file.is_some()) && conditions.iter().any(|&x| x) {
+            return Err("--stdin and --from-file only give files to trash or restore".to_string());
+        }
+
+        let restoring = self.undo
+            || matches!(self.command, Some(Commands::Undo { .. }))
+            || matches!(self.command, Some(Commands::Versions { restore: Some(_), .. }));
+        if (self.to.is_some() || self.as_name.is_some()) && matches!(self.command, Some(Commands::Restore { .. })) {
+            return Err("restore --at puts the files back where they were, --to and --as do not apply".to_string());
+        }
+        if (self.to.is_some() || self.as_name.is_some()) && !restoring {
+            return Err("--to and --as only apply when restoring".to_string());
+        }
+
+        if let Some(name) = &self.as_name {
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                return Err(format!("--as takes a file name, not {:?}", name));
+            }
+        }
+
+        if self.dry_run && matches!(self.command, Some(Commands::Compress { .. })) {
+            return Err("compress does not support --dry-run".to_string());
+        }
+
+        if self.stdin && self.from_file.is_some() {
+            return Err("Cannot combine --stdin and --from-file".to_string());
+        }
+
+        if let Some(max_percent) = self.max_percent {
+            if !(max_percent > 0.0 && max_percent <= 100.0) {
+                return Err("--max-percent must be more than 0 and at most 100".to_string());
+            }
+        }
+
         // list, undo and all cannot be combined
         if self.list && self.undo && self.all{
             return Err("Cannot combine --list, --undo and --all. If you want to restore all files in trash, use --undo and --all".to_string());
@@ -138,101 +356,334 @@ impl Args{
 
 /// This does the following
 ///
-/// 1. Create a info file, which stores the name and time at which it was moved here
-/// 2. Move the file
-pub fn move_files(args: &Args, dir_path: &PathBuf, files: &Vec<PathBuf>) {
+/// 1. Move every file into the trash
+/// 2. Create a info file, which stores the name and time at which it was moved here
+///
+/// The batch is all-or-nothing: if any file fails to move, or the log cannot be
+/// written, the files already moved are put back where they came from.
+/// With `keep_going`, a file which fails to move is reported and the others are still trashed.
+/// Returns the files which failed that way.
+/// `reverts` is the ID of the restore being redone, if any
+pub fn move_files(args: &Args, dir_path: &PathBuf, files: &[PathBuf], reverts: Option<&str>) -> Vec<PathBuf> {
+    if let Err(reason) = protect::check(args, dir_path, files) {
+        eprintln!("{}", reason);
+        std::process::exit(1);
+    }
+
+    if args.dry_run {
+        plan_moves(args, dir_path, files);
+        return vec![];
+    }
+
+    let files = &prompt::select(args, files);
+    if files.is_empty() {
+        return vec![];
+    }
+
     let mut src_files: Vec<String> = Vec::with_capacity(files.len());
     let mut dst_files: Vec<String> = Vec::with_capacity(files.len());
+    let mut metas: Vec<ItemMeta> = Vec::with_capacity(files.len());
+    let mut failure: Option<String> = None;
+    let mut failed: Vec<PathBuf> = vec![];
+    let key = if args.encrypt { Some(crypt::load_key(args)) } else { None };
+
+    let mut journal = match Journal::begin(OpType::TRASH, reverts.map(str::to_string)) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("Failed to start journal: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     for file in files {
-        let full_path = match file.canonicalize() {
-            Ok(path) => path,
-            Err(e) => {
-                eprintln!("Failed to canonicalize path {}: {}", file.display(), e);
-                return;
+        let located = prepare_item(args, dir_path, file).and_then(|(full_path, meta)| {
+            // files on another filesystem go to the store on that filesystem, so the move is a rename
+            let store = store::store_for(&full_path, dir_path);
+            let new_location = trash_location(args, &store, &full_path, utils::path_exists)?;
+
+            // ensuring parent directories exist
+            if let Some(parent) = new_location.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create directory {}: {}", parent.display(), e))?;
             }
-        };
-
-        let mut new_location = dir_path.join(full_path.strip_prefix("/").unwrap());
-
-        // ensuring parent directories exist
-        if let Some(parent) = new_location.clone().parent() {
-            // if name conflict exists, find the version number in a 2 step process
-            // 1. Binary exponentiation to find upper limit
-            // 2. Binary search to find actual number
-            if new_location.exists() {
-                let mut search_start = 1;
-                let mut search_end = 1;
-                let file_name = get_file_name!(file);
-                while parent
-                    .join(format!("{}_{}", file_name, &search_end.to_string()))
-                    .exists()
-                {
-                    search_start = search_end;
-                    search_end *= 2;
-                }
-
-                // Binary search
-                while search_start < search_end {
-                    let middle = (search_start + search_end) / 2;
-                    let curr_file_name =
-                        parent.join(format!("{}_{}", file_name, &middle.to_string()));
-
-                    if curr_file_name.exists() {
-                        search_start = middle + 1;
-                    } else {
-                        search_end = middle;
-                    }
-                }
-
-                let new_file_name = format!("{}_{}", file_name, search_end);
-                new_location.set_file_name(&new_file_name);
-
-                if args.debug {
-                    println!("New file name: {}", new_file_name);
-                    println!("New file location: {}", new_location.display());
-                }
+            Ok((full_path, meta, new_location))
+        });
+        let (full_path, meta, new_location) = match located {
+            Ok(located) => located,
+            Err(reason) if args.keep_going => {
+                eprintln!("{}", reason);
+                failed.push(file.clone());
+                continue;
             }
-
-            if let Err(e) = fs::create_dir_all(parent) {
-                eprintln!("Failed to create directory {}: {}", parent.display(), e);
-                std::process::exit(1);
+            Err(reason) => {
+                failure = Some(reason);
+                break;
             }
-        }
+        };
 
         if args.debug {
             println!("New file location: {}", new_location.display());
         }
 
-        match utils::move_content(&full_path, &new_location) {
+        let journaled = match key {
+            Some(_) => journal.encrypt_intent(&full_path, &new_location),
+            None => journal.intent(&full_path, &new_location),
+        };
+        if let Err(e) = journaled {
+            failure = Some(format!("Failed to write journal: {}", e));
+            break;
+        }
+
+        let moved = match &key {
+            Some(key) => crypt::encrypt_item(key, &full_path, &new_location),
+            None => utils::move_content(&full_path, &new_location),
+        };
+        match moved {
             Ok(_) => {
                 if args.verbose {
                     println!("Successfully moved {} to trash", full_path.display());
                 }
                 src_files.push(full_path.display().to_string());
                 dst_files.push(new_location.display().to_string());
+                metas.push(meta);
+
+                if let Err(e) = journal.moved(&full_path, &new_location) {
+                    failure = Some(format!("Failed to write journal: {}", e));
+                    break;
+                }
             }
             Err(e) => {
-                eprintln!(
+                let reason = format!(
                     "Failed to move files from {} to {}: {}",
                     full_path.display(),
                     new_location.display(),
                     e
                 );
-                std::process::exit(1);
+                if args.keep_going {
+                    eprintln!("{}", reason);
+                    failed.push(file.clone());
+                    continue;
+                }
+                failure = Some(reason);
+                break;
             }
         }
     }
 
-    if let Err(e) = append_to_logs(&FileInfo {
-        src: src_files,
-        dst: dst_files,
-        operation: OpType::TRASH,
-        moved_time: Local::now(),
-    }) {
-        eprintln!("Failed to append to logs: {}", e);
+    if failure.is_none() && src_files.is_empty() {
+        journal.commit();
+        return failed;
+    }
+
+    if failure.is_none() {
+        let logged_src = match &key {
+            Some(key) if args.encrypt_paths => src_files.iter().map(|src| crypt::seal_path(key, src)).collect(),
+            _ => Ok(src_files.clone()),
+        };
+        let appended = logged_src.and_then(|logged_src| {
+            append_to_logs(&FileInfo {
+                src: logged_src,
+                dst: dst_files.clone(),
+                operation: OpType::TRASH,
+                moved_time: journal.started_time,
+                meta: metas,
+                archived: BTreeMap::new(),
+                encrypted: key.is_some(),
+                reverts: journal.reverts.clone(),
+            })
+        });
+        if let Err(e) = appended {
+            failure = Some(format!("Failed to append to logs: {}", e));
+        }
+    }
+
+    if let Some(reason) = failure {
+        eprintln!("{}", reason);
+        // if some files could not be moved back, the journal is kept so that the next run retries
+        if rollback_moves(args, dir_path, key.as_ref(), &src_files, &dst_files) {
+            journal.commit();
+        }
         std::process::exit(1);
     }
+
+    let moved_time = journal.started_time;
+    journal.commit();
+
+    // an encrypted item is a single file, so there is nothing to share
+    if args.dedup && key.is_none() {
+        let stores = store::all_stores(dir_path);
+        for dst in &dst_files {
+            let dst = PathBuf::from(dst);
+            let Some(store) = store::store_of(&dst, &stores) else {
+                continue;
+            };
+            match dedup::store_item(store, &dst) {
+                Ok(saved) if args.verbose && saved > 0 => {
+                    println!("Saved {} by deduplicating {}", HumanBytes(saved), dst.display())
+                }
+                Ok(_) => {}
+                // the item is in the trash either way, only the space saving is lost
+                Err(e) => eprintln!("Failed to deduplicate {}: {}", dst.display(), e),
+            }
+        }
+    }
+
+    quota::enforce(args, dir_path, moved_time);
+    archive::spawn_background(args, dir_path);
+    failed
+}
+
+/// Resolve a file to be trashed and capture its metadata, checking that it exists and fits in the trash
+fn prepare_item(args: &Args, dir_path: &Path, file: &Path) -> Result<(PathBuf, ItemMeta), String> {
+    // only the parent is resolved, so symlinks are trashed as links
+    let full_path = match utils::absolute_path(file) {
+        Ok(path) if utils::path_exists(&path) => path,
+        Ok(_) => return Err(format!("Failed to trash {}: No such file or directory", file.display())),
+        Err(e) => return Err(format!("Failed to canonicalize path {}: {}", file.display(), e)),
+    };
+
+    // a plain checksum would tell about the contents of an encrypted item, whose encryption
+    // already detects any change to it
+    let hash = args.hash && !args.encrypt && !args.dry_run;
+    let meta = ItemMeta::capture(&full_path, hash)
+        .map_err(|e| format!("Failed to read metadata of {}: {}", full_path.display(), e))?;
+
+    quota::check_item(args, dir_path, &full_path, meta.disk_usage)?;
+
+    Ok((full_path, meta))
+}
+
+/// Where in `store` a file goes. `taken` tells whether a name in the trash is already in use
+fn trash_location(
+    args: &Args,
+    store: &Path,
+    full_path: &Path,
+    taken: impl Fn(&Path) -> bool,
+) -> Result<PathBuf, String> {
+    if args.encrypt_paths {
+        // the location in the trash would give the original path away
+        return crypt::vault_path(store).map_err(|e| format!("Failed to pick a name in the trash: {}", e));
+    }
+
+    let mut new_location = store.join(full_path.strip_prefix("/").unwrap());
+    if taken(&new_location) {
+        new_location = utils::free_name(&new_location, taken);
+
+        if args.debug {
+            println!("New file name: {}", get_file_name!(new_location));
+            println!("New file location: {}", new_location.display());
+        }
+    }
+
+    Ok(new_location)
+}
+
+/// Show where every file would go and which old items the size limit would then delete.
+/// Like a real run, nothing is planned if any of the files could not be trashed
+fn plan_moves(args: &Args, dir_path: &Path, files: &[PathBuf]) {
+    let mut plan = Plan::new();
+    let mut planned: Vec<PathBuf> = vec![];
+    let mut added: Vec<(PathBuf, u64)> = vec![];
+    let mut sources: Vec<PathBuf> = vec![];
+
+    for file in files {
+        // a file inside one trashed earlier in the batch is gone by the time it is reached
+        let moved_earlier = utils::absolute_path(file)
+            .is_ok_and(|path| sources.iter().any(|src: &PathBuf| path.starts_with(src)));
+        let result = if moved_earlier {
+            Err(format!("Failed to trash {}: No such file or directory", file.display()))
+        } else {
+            prepare_item(args, dir_path, file)
+        };
+        let result = result.and_then(|(full_path, meta)| {
+            let store = store::locate(&full_path, dir_path);
+            // files earlier in the batch take their names first, and create the directories above them
+            let taken = |path: &Path| utils::path_exists(path) || planned.iter().any(|dst| dst.starts_with(path));
+            let new_location = trash_location(args, &store, &full_path, taken)?;
+            Ok((full_path, meta, store, new_location))
+        });
+        let (full_path, meta, store, new_location) = match result {
+            Ok(planned) => planned,
+            Err(reason) => {
+                eprintln!("{}", reason);
+                eprintln!("Nothing would be trashed");
+                std::process::exit(1);
+            }
+        };
+
+        let mut notes = vec![format!("{}, {}", meta.file_type, HumanBytes(meta.size))];
+        if args.encrypt {
+            notes.push("encrypted".to_string());
+        }
+        if store != dir_path {
+            notes.push(format!("store on another filesystem {}", store.display()));
+        }
+        plan.add(Kind::Trash, &full_path, Some(&new_location), notes);
+        added.push((store, meta.disk_usage));
+        planned.push(new_location);
+        sources.push(full_path);
+    }
+
+    quota::plan(args, dir_path, &added, &mut plan);
+    plan.print(args);
+}
+
+/// Put back files which were moved to the trash by a batch that could not be completed.
+/// Files are moved back in the reverse order of how they were trashed.
+/// Returns whether every file was moved back
+fn rollback_moves(
+    args: &Args,
+    dir_path: &Path,
+    key: Option<&crypt::Key>,
+    src_files: &[String],
+    dst_files: &[String],
+) -> bool {
+    if src_files.is_empty() {
+        eprintln!("No files were moved to trash");
+        return true;
+    }
+
+    let stores = store::all_stores(dir_path);
+    let mut stranded: Vec<(&String, std::io::Error)> = vec![];
+
+    for (src, dst) in src_files.iter().zip(dst_files).rev() {
+        let src_path = PathBuf::from(src);
+        let dst_path = PathBuf::from(dst);
+
+        let moved_back = match key {
+            Some(key) => crypt::decrypt_item(key, &dst_path, &src_path),
+            None => utils::move_content(&dst_path, &src_path),
+        };
+        match moved_back {
+            Ok(_) => {
+                if args.verbose {
+                    println!("Rolled back {}", src_path.display());
+                }
+                if let Some(store) = store::store_of(&dst_path, &stores) {
+                    utils::remove_empty_parents(&dst_path, store);
+                }
+            }
+            Err(e) => stranded.push((dst, e)),
+        }
+    }
+
+    if stranded.is_empty() {
+        eprintln!(
+            "Rolled back {} file(s) already moved to trash, nothing was trashed",
+            src_files.len()
+        );
+        return true;
+    }
+
+    eprintln!(
+        "Rolled back {} of {} file(s). The following could not be moved back and are still in trash:",
+        src_files.len() - stranded.len(),
+        src_files.len()
+    );
+    for (dst, e) in stranded {
+        eprintln!("  {}: {}", dst, e);
+    }
+    false
 }
 
 pub fn list_delete_files(
@@ -246,29 +697,79 @@ pub fn list_delete_files(
         files.push(cwd.clone());
     }
 
+    let stores = store::all_stores(dir_path);
     let mut deleted_files: Vec<Vec<PathBuf>> = vec![];
+    let items = if args.verbose { trashed_items() } else { HashMap::new() };
+    let archived = archived_items();
+    let vault = crypt::vault_key(args).map(|key| crypt::vault_items(&key)).unwrap_or_default();
 
     for file in files.iter_mut() {
         let full_path = match file.canonicalize() {
             Ok(path) => path,
             Err(_) => cwd.clone().join(&file),
         };
+        let relative_path = full_path.strip_prefix("/").unwrap();
+
+        *file = dir_path.join(relative_path);
+
+        // the directory can have trashed files in more than one store
+        let mut all_sub_files: Vec<PathBuf> = vec![];
+        for store in &stores {
+            let store_dir = store.join(relative_path);
+            let mut compressed: Vec<PathBuf> = archived
+                .keys()
+                .map(PathBuf::from)
+                .filter(|path| path.parent() == Some(&store_dir) && !utils::path_exists(path))
+                .collect();
+            compressed.sort();
+
+            let sub_files: Vec<PathBuf> = if store_dir.is_dir() {
+                fs::read_dir(&store_dir)?
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|path| {
+                        !dedup::is_blobs_dir(path) && !archive::is_archive_dir(path) && !crypt::is_vault_dir(path)
+                    })
+                    .collect()
+            } else {
+                vec![]
+            };
+
+            if args.verbose && (!sub_files.is_empty() || !compressed.is_empty()) {
+                println!("{}:", store_dir.display());
+                let all_files: Vec<PathBuf> = sub_files.iter().chain(&compressed).cloned().collect();
+                display_details(&all_files, &items, &archived);
+            } else {
+                if !sub_files.is_empty() {
+                    println!("{}:", store_dir.display());
+                    utils::display_files(&sub_files, true);
+                }
+                if !compressed.is_empty() {
+                    println!("{} (compressed):", store_dir.display());
+                    utils::display_files(&compressed, true);
+                }
+            }
+            all_sub_files.extend(sub_files);
+            all_sub_files.extend(compressed);
+        }
 
-        *file = dir_path.join(full_path.strip_prefix("/").unwrap());
-
-        let sub_files: Vec<PathBuf> = fs::read_dir(&file)?
-            .filter_map(|entry| entry.ok().map(|e| e.path()))
+        // items with encrypted paths are found by their original path, and shown by it
+        let hidden: Vec<&(PathBuf, PathBuf)> = vault
+            .iter()
+            .filter(|(_, original)| original.parent() == Some(&full_path))
             .collect();
-
-        if return_list {
-            deleted_files.push(sub_files.clone());
+        if !hidden.is_empty() {
+            println!("{} (encrypted paths):", full_path.display());
+            let originals: Vec<PathBuf> = hidden.iter().map(|(_, original)| original.clone()).collect();
+            utils::display_files(&originals, true);
+            all_sub_files.extend(hidden.iter().map(|(dst, _)| dst.clone()));
         }
 
-        if sub_files.len() == 0 {
+        if all_sub_files.is_empty() {
             println!("No files found under {}", file.display());
-        } else {
-            println!("{}:", file.display().to_string());
-            utils::display_files(&sub_files, true);
+        }
+
+        if return_list {
+            deleted_files.push(all_sub_files);
         }
     }
 
@@ -283,84 +784,762 @@ pub fn list_delete_files(
     Ok(vec![])
 }
 
-pub fn recover_files(args: &Args, dir_path: &PathBuf, files: &mut Vec<PathBuf>, from_trash: bool) {
-    let cwd = std::env::current_dir().unwrap();
+/// Show the recorded metadata of trashed paths as a table. Paths trashed as part of a
+/// directory, or by an older version, have no metadata of their own
+fn display_details(files: &[PathBuf], items: &HashMap<String, ItemMeta>, archived: &HashMap<String, String>) {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_header(vec!["Name", "Type", "Size", "Permissions", "Modified", "Trashed"]);
+
+    let mut trashed_times: HashMap<String, DateTime<Local>> = HashMap::new();
+    if Path::new(&get_log_file()).exists() {
+        for log in read_logs(Filter::All) {
+            if log.operation == OpType::TRASH {
+                for dst in log.dst {
+                    trashed_times.insert(dst, log.moved_time);
+                }
+            }
+        }
+    }
 
-    let mut src_files: Vec<String> = Vec::with_capacity(files.len());
-    let mut dst_files: Vec<String> = Vec::with_capacity(files.len());
+    for file in files {
+        let name = get_file_name!(file).to_string();
+        let key = file.display().to_string();
+        let trashed = trashed_times
+            .get(&key)
+            .map_or(String::new(), |time| time.format("%Y-%m-%d %H:%M").to_string());
+        let compressed = archived.contains_key(&key) && !utils::path_exists(file);
+        let file_type = |file_type: &str| {
+            if compressed {
+                format!("{}, compressed", file_type)
+            } else {
+                file_type.to_string()
+            }
+        };
+        match items.get(&key) {
+            Some(meta) => table.add_row(vec![
+                name,
+                file_type(&meta.file_type),
+                HumanBytes(meta.size).to_string(),
+                meta.permissions(),
+                meta.mtime.format("%Y-%m-%d %H:%M").to_string(),
+                trashed,
+            ]),
+            None => table.add_row(vec![name, file_type(""), String::new(), String::new(), String::new(), trashed]),
+        };
+    }
+
+    println!("{}", table);
+}
+
+/// Where a restored item goes: its original path, unless --to or --as say otherwise
+fn destination(args: &Args, cwd: &Path, original: PathBuf) -> PathBuf {
+    let dir = match &args.to {
+        Some(dir) => {
+            let dir = cwd.join(dir);
+            dir.canonicalize().unwrap_or(dir)
+        }
+        None => match original.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => return original,
+        },
+    };
+    match (&args.as_name, original.file_name()) {
+        (Some(name), _) => dir.join(name),
+        (None, Some(name)) => dir.join(name),
+        (None, None) => original,
+    }
+}
+
+/// --as gives a single file a new name, so it makes no sense for more than one
+fn check_single(args: &Args, files: &[PathBuf]) {
+    if args.as_name.is_some() && files.len() > 1 {
+        eprintln!("--as renames a single file, but {} files would be restored", files.len());
+        std::process::exit(1);
+    }
+}
+
+/// What is known about the trash when looking for files to restore
+struct Lookup {
+    cwd: PathBuf,
+    stores: Vec<PathBuf>,
+    archived: HashMap<String, String>,
+    vault: Vec<(PathBuf, PathBuf)>,
+    trashed: Vec<TrashedItem>,
+}
+
+impl Lookup {
+    fn new(dir_path: &Path, vault: Vec<(PathBuf, PathBuf)>) -> Lookup {
+        Lookup {
+            cwd: std::env::current_dir().unwrap(),
+            stores: store::all_stores(dir_path),
+            archived: archived_items(),
+            vault,
+            trashed: trashed_originals(),
+        }
+    }
+}
+
+/// Find a file to be restored and where it goes back to. `file` is changed to its path in the
+/// trash. An original path which was trashed more than once gives its latest version.
+/// Compressed items come with the archive they are extracted from
+fn locate_restore(
+    file: &mut PathBuf,
+    from_trash: bool,
+    lookup: &Lookup,
+) -> Result<(PathBuf, Option<PathBuf>), String> {
+    let mut full_path = match utils::absolute_path(file) {
+        Ok(path) => path,
+        Err(_) => lookup.cwd.join(&file),
+    };
+    if !from_trash {
+        *file = match lookup.trashed.iter().rev().find(|item| item.original == full_path) {
+            Some(item) => item.dst.clone(),
+            None => store::trash_path(&full_path, &lookup.stores),
+        };
+        if !utils::path_exists(file) && !lookup.archived.contains_key(&file.display().to_string()) {
+            if let Some((dst, _)) = lookup.vault.iter().rev().find(|(_, original)| *original == full_path) {
+                *file = dst.clone();
+            }
+        }
+    } else if crypt::in_vault(&full_path) {
+        match lookup.vault.iter().find(|(dst, _)| *dst == full_path) {
+            Some((_, original)) => full_path = original.clone(),
+            None => return Err(format!("Unable to move {}: Its original path is encrypted", file.display())),
+        }
+    } else if let Some(item) = lookup.trashed.iter().find(|item| item.dst == full_path) {
+        // a version trashed as `foo_1` goes back to `foo`
+        full_path = item.original.clone();
+    } else {
+        match store::store_of(&full_path, &lookup.stores) {
+            Some(store) => {
+                full_path = PathBuf::from("/").join(full_path.strip_prefix(store).unwrap());
+            }
+            None => return Err(format!("Unable to move {}: Not a file in trash", file.display())),
+        }
+    }
+
+    // compressed items are extracted from their archive instead
+    if utils::path_exists(file) {
+        Ok((full_path, None))
+    } else if let Some(archive) = lookup.archived.get(&file.display().to_string()) {
+        Ok((full_path, Some(PathBuf::from(archive))))
+    } else {
+        Err(format!("Unable to move {}: No such file exists", file.display()))
+    }
+}
+
+/// A file to be restored
+struct Restore {
+    /// Its path in the trash
+    file: PathBuf,
+    target: PathBuf,
+    /// The archive it is extracted from, when it was compressed
+    archive: Option<PathBuf>,
+    /// What is in the way of restoring it and what is done about it
+    conflict: Option<(PathBuf, Resolution)>,
+}
+
+/// Find every file to be restored, where it goes and what to do if something is in the way.
+/// Files which cannot be found are reported and left out
+fn plan_items(args: &Args, files: &mut [PathBuf], from_trash: bool, lookup: &Lookup) -> Vec<Restore> {
+    let mut restores: Vec<Restore> = vec![];
 
     for file in files.iter_mut() {
-        let mut full_path = match file.canonicalize() {
-            Ok(path) => path,
-            Err(_) => cwd.clone().join(&file),
+        let (original, archive) = match locate_restore(file, from_trash, lookup) {
+            Ok(located) => located,
+            Err(reason) => {
+                eprintln!("{}", reason);
+                continue;
+            }
         };
-        if !from_trash {
-            *file = dir_path.join(full_path.strip_prefix("/").unwrap());
+        let mut target = destination(args, &lookup.cwd, original);
+
+        // two files of the same restore can only both be kept
+        let in_batch = |path: &Path| restores.iter().any(|restore: &Restore| restore.target == path);
+        let resolution = if in_batch(&target) {
+            Some(Resolution::Rename)
+        } else if utils::path_exists(&target) {
+            Some(conflict::resolve(args, file, &target))
         } else {
-            full_path = PathBuf::from("/").join(full_path.strip_prefix(dir_path).unwrap());
+            None
+        };
+        let conflict = resolution.map(|resolution| (target.clone(), resolution));
+        if resolution == Some(Resolution::Rename) {
+            target = utils::free_name(&target, |path| utils::path_exists(path) || in_batch(path));
         }
 
-        if file.exists() {
-            match utils::move_content(&file, &full_path) {
-                Ok(_) => {
-                    if args.verbose {
-                        println!(
-                            "Successfully recovered file from trash to {}",
-                            full_path.display()
-                        );
-                    }
+        restores.push(Restore { file: file.clone(), target, archive, conflict });
+    }
+
+    restores
+}
 
-                    src_files.push(file.display().to_string());
-                    dst_files.push(full_path.display().to_string());
+/// Show where every file would be restored to
+fn plan_restores(args: &Args, dir_path: &Path, files: &mut [PathBuf], from_trash: bool) {
+    check_single(args, files);
+    let encrypted = encrypted_items();
+    let vault = crypt::vault_key(args).map(|key| crypt::vault_items(&key)).unwrap_or_default();
+    let lookup = Lookup::new(dir_path, vault);
+    let mut plan = Plan::new();
+
+    for restore in plan_items(args, files, from_trash, &lookup) {
+        let mut notes: Vec<String> = vec![];
+        if let Some(archive) = &restore.archive {
+            notes.push(format!("compressed in {}", archive.display()));
+        }
+        if encrypted.contains(&restore.file.display().to_string()) {
+            notes.push("encrypted".to_string());
+        }
+        if let Some((existing, resolution)) = &restore.conflict {
+            let in_the_way = vec![format!("in the way of {}", restore.file.display())];
+            match resolution {
+                Resolution::Skip => {
+                    let notes = vec![format!("{} already exists", existing.display())];
+                    plan.add(Kind::Skip, &restore.file, Some(&restore.target), notes);
+                    continue;
                 }
+                Resolution::Overwrite => plan.add(Kind::Delete, existing, None, in_the_way),
+                Resolution::TrashExisting => plan.add(Kind::Trash, existing, None, in_the_way),
+                Resolution::Rename | Resolution::Ask => {}
+            }
+            notes.push(conflict::report(*resolution, existing, &restore.target));
+        }
+        plan.add(Kind::Restore, &restore.file, Some(&restore.target), notes);
+    }
+
+    plan.print(args);
+}
+
+/// Restore files from the trash. `reverts` is the ID of the operation being undone, if any.
+/// Paths which are taken are dealt with as --on-conflict says, and every conflict is reported
+pub fn recover_files(
+    args: &Args,
+    dir_path: &PathBuf,
+    files: &mut Vec<PathBuf>,
+    from_trash: bool,
+    reverts: Option<&str>,
+) {
+    if args.dry_run {
+        plan_restores(args, dir_path, files, from_trash);
+        return;
+    }
+
+    check_single(args, files);
+    if let Some(dir) = &args.to {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Failed to create directory {}: {}", dir, e);
+            std::process::exit(1);
+        }
+    }
+
+    let mut key = crypt::vault_key(args);
+    let vault = key.as_ref().map(crypt::vault_items).unwrap_or_default();
+    let restores = plan_items(args, files, from_trash, &Lookup::new(dir_path, vault));
+
+    // what is in the way goes to the trash as an operation of its own, before anything is restored
+    let in_the_way: Vec<PathBuf> = restores
+        .iter()
+        .filter_map(|restore| match &restore.conflict {
+            Some((existing, Resolution::TrashExisting)) => Some(existing.clone()),
+            _ => None,
+        })
+        .collect();
+    if !in_the_way.is_empty() {
+        move_files(args, dir_path, &in_the_way, None);
+    }
+
+    let mut src_files: Vec<String> = Vec::with_capacity(restores.len());
+    let mut dst_files: Vec<String> = Vec::with_capacity(restores.len());
+
+    let mut journal = match Journal::begin(OpType::RESTORE, reverts.map(str::to_string)) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("Failed to start journal: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let stores = store::all_stores(dir_path);
+    let items = trashed_items();
+    let encrypted = encrypted_items();
+    let mut extracted: Vec<String> = vec![];
+    let mut failed = false;
+    let progress = Progress::new("Restoring", 0, restores.len() as u64);
+
+    for restore in &restores {
+        progress.inc(0, 1);
+        let file = &restore.file;
+        let full_path = &restore.target;
+        let archive = &restore.archive;
+        let decrypt = archive.is_none() && encrypted.contains(&file.display().to_string());
+
+        // nothing is changed for an item which cannot be decrypted
+        if decrypt && key.is_none() {
+            match crypt::try_load_key(args) {
+                Ok(loaded) => key = Some(loaded),
                 Err(e) => {
-                    eprintln!(
-                        "Failed to move files from {} to {}: {}",
-                        file.display(),
-                        full_path.display(),
-                        e
+                    eprintln!("{}", e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+
+        // a file to be overwritten is only moved aside, and deleted once the item is in its place
+        let mut aside: Option<(&PathBuf, PathBuf)> = None;
+        if let Some((existing, resolution)) = &restore.conflict {
+            if *resolution == Resolution::Overwrite {
+                let aside_path = utils::aside_path(existing, std::process::id());
+                let moved_aside = journal
+                    .aside(existing, &aside_path)
+                    .and_then(|_| fs::rename(existing, &aside_path));
+                if let Err(e) = moved_aside {
+                    eprintln!("Failed to move {} out of the way: {}", existing.display(), e);
+                    failed = true;
+                    continue;
+                }
+                aside = Some((existing, aside_path));
+            }
+            eprintln!("{}", conflict::report(*resolution, existing, full_path));
+            if *resolution == Resolution::Skip {
+                continue;
+            }
+        }
+
+        let journaled = match &archive {
+            Some(archive) => journal.extract_intent(file, full_path, archive),
+            None => journal.intent(file, full_path),
+        };
+        if let Err(e) = journaled {
+            eprintln!("Failed to write journal: {}", e);
+            put_back(aside);
+            failed = true;
+            break;
+        }
+
+        let restored = match &archive {
+            Some(archive) => archive::extract(archive, file, full_path),
+            None if decrypt => crypt::decrypt_item(key.as_ref().unwrap(), file, full_path),
+            None => utils::move_content(file, full_path),
+        };
+        if restored.is_err() {
+            put_back(aside.take());
+        }
+        match restored {
+            Ok(_) => {
+                if let Some((_, aside_path)) = &aside {
+                    if let Err(e) = utils::remove_path(aside_path) {
+                        eprintln!("Failed to delete {}: {}", aside_path.display(), e);
+                    }
+                }
+
+                if args.verbose {
+                    println!(
+                        "Successfully recovered file from trash to {}",
+                        full_path.display()
                     );
                 }
+
+                if let Some(store) = store::store_of(file, &stores) {
+                    if let Err(e) = dedup::restore_item(store, file, full_path) {
+                        eprintln!("Failed to unshare {} from the trash: {}", full_path.display(), e);
+                    }
+                }
+
+                let recorded_hash = items.get(&file.display().to_string()).and_then(|meta| meta.hash.as_ref());
+                if let Some(recorded_hash) = recorded_hash {
+                    match copy::hash_tree(full_path) {
+                        Ok(hash) if &hash == recorded_hash => {
+                            if args.verbose {
+                                println!("Checksum of {} matches", full_path.display());
+                            }
+                        }
+                        Ok(_) => eprintln!(
+                            "Warning: {} differs from when it was trashed",
+                            full_path.display()
+                        ),
+                        Err(e) => eprintln!("Failed to verify {}: {}", full_path.display(), e),
+                    }
+                }
+
+                src_files.push(file.display().to_string());
+                dst_files.push(match &key {
+                    Some(key) if crypt::in_vault(file) => crypt::seal_path(key, &full_path.display().to_string())
+                        .unwrap_or_else(|_| crypt::HIDDEN_PATH.to_string()),
+                    _ => full_path.display().to_string(),
+                });
+                if archive.is_some() {
+                    extracted.push(file.display().to_string());
+                }
+
+                if let Err(e) = journal.moved(file, full_path) {
+                    eprintln!("Failed to write journal: {}", e);
+                    break;
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to move files from {} to {}: {}",
+                    file.display(),
+                    full_path.display(),
+                    e
+                );
+                failed = true;
             }
-        } else {
-            eprintln!("Unable to move {}: No such file exists", file.display());
         }
     }
 
     if src_files.is_empty(){
+        journal.commit();
+        if failed {
+            std::process::exit(1);
+        }
         return;
     }
 
+    // blobs only the restored files linked to are not needed anymore
+    for store in &stores {
+        dedup::collect_garbage(store);
+    }
+
+    // on failure the journal is kept, so the next run records what was restored
     if let Err(e) = append_to_logs(&FileInfo {
         src: src_files,
         dst: dst_files,
         operation: OpType::RESTORE,
-        moved_time: Local::now(),
+        moved_time: journal.started_time,
+        meta: vec![],
+        archived: BTreeMap::new(),
+        encrypted: false,
+        reverts: journal.reverts.clone(),
     }) {
         eprintln!("Failed to append to logs: {}", e);
         std::process::exit(1);
     }
+
+    if let Err(e) = archive::forget_restored(&extracted) {
+        eprintln!("Failed to update compressed items in logs: {}", e);
+    }
+
+    journal.commit();
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Move a file which was to be overwritten back to its place, as the restore did not happen
+fn put_back(aside: Option<(&PathBuf, PathBuf)>) {
+    if let Some((existing, aside_path)) = aside {
+        if let Err(e) = fs::rename(&aside_path, existing) {
+            eprintln!("Failed to move {} back to {}: {}", aside_path.display(), existing.display(), e);
+        }
+    }
 }
 
-/// List all files in trash
-pub fn list_all_files(dir_path: &PathBuf, return_list: bool) -> Vec<PathBuf>{
+/// List all files in trash, across every store
+pub fn list_all_files(args: &Args, dir_path: &Path, return_list: bool) -> Vec<PathBuf>{
     let mut files: Vec<PathBuf> = Vec::with_capacity(1000);
 
-    for file in walkdir::WalkDir::new(dir_path){
-        files.push(file.unwrap().path().to_path_buf());
+    for store in store::all_stores(dir_path) {
+        let walker = walkdir::WalkDir::new(store)
+            .into_iter()
+            .filter_entry(|entry| {
+                !dedup::is_blobs_dir(entry.path())
+                    && !archive::is_archive_dir(entry.path())
+                    && !crypt::is_vault_dir(entry.path())
+            });
+        for file in walker{
+            files.push(file.unwrap().path().to_path_buf());
+        }
     }
 
+    let mut compressed: Vec<PathBuf> = archived_items()
+        .into_keys()
+        .map(PathBuf::from)
+        .filter(|path| !utils::path_exists(path))
+        .collect();
+    compressed.sort();
+
+    let vault = crypt::vault_key(args).map(|key| crypt::vault_items(&key)).unwrap_or_default();
+
     if return_list{
+        files.extend(compressed);
+        files.extend(vault.into_iter().map(|(dst, _)| dst));
         return files;
     } else{
         utils::display_files(&files, false);
+        if !compressed.is_empty(){
+            println!("Compressed:");
+            utils::display_files(&compressed, false);
+        }
+        if !vault.is_empty(){
+            println!("Encrypted paths:");
+            utils::display_files(&vault.into_iter().map(|(_, original)| original).collect(), false);
+        }
     }
 
     vec![]
 }
 
-/// Recover all files from trash
+/// Recover all files from trash.
+/// The log is used to find what was trashed, so that every store is covered and only
+/// trashed items are moved back instead of every directory in the trash
 pub fn recover_all_files(args: &Args, dir_path: &PathBuf){
-    let mut all_trash_files = list_all_files(&dir_path, true);
-    recover_files(args, dir_path, &mut all_trash_files, true);
-}
\ No newline at end of file
+    if !Path::new(&get_log_file()).exists() {
+        eprintln!("No files in trash");
+        return;
+    }
+
+    let mut all_trash_files: Vec<PathBuf> = vec![];
+    for log in read_logs(Filter::All) {
+        if log.operation != OpType::TRASH {
+            continue;
+        }
+        for dst in log.dst {
+            let dst = PathBuf::from(dst);
+            let archived = log.archived.contains_key(&dst.display().to_string());
+            if (archived || utils::path_exists(&dst)) && !all_trash_files.contains(&dst) {
+                all_trash_files.push(dst);
+            }
+        }
+    }
+
+    recover_files(args, dir_path, &mut all_trash_files, true, None);
+}
+/// The items of the trash operation at `index` which are still in the trash. Items restored
+/// since, or trashed again by a later operation, are left alone
+fn remaining_items(logs: &[FileInfo], owner: &HashMap<&String, usize>, index: usize) -> Vec<PathBuf> {
+    let log = &logs[index];
+    log.dst
+        .iter()
+        .filter(|dst| owner.get(dst) == Some(&index))
+        .filter(|dst| log.archived.contains_key(*dst) || utils::path_exists(Path::new(dst)))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Restore the items of one trash operation which are still in the trash
+pub fn undo_operation(args: &Args, dir_path: &PathBuf, id: &str) {
+    if !Path::new(&get_log_file()).exists() {
+        eprintln!("No files in trash");
+        std::process::exit(1);
+    }
+
+    let logs = read_logs(Filter::All);
+    let index = match find_operation(&logs, id) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let log = &logs[index];
+    if log.operation != OpType::TRASH {
+        eprintln!("Operation {} is a restore, only trash operations can be undone", log.id());
+        std::process::exit(1);
+    }
+
+    let mut files = remaining_items(&logs, &owners(&logs), index);
+    if files.is_empty() {
+        eprintln!("Nothing trashed by operation {} is left in the trash", log.id());
+        std::process::exit(1);
+    }
+
+    recover_files(args, dir_path, &mut files, true, Some(&log.id()));
+}
+
+/// Undo the latest trash operations which still have items in the trash, the newest first:
+/// `last` of them, or the ones done within `since`. Each is recorded as a restore of its own
+pub fn undo_recent(args: &Args, dir_path: &PathBuf, last: Option<usize>, since: Option<std::time::Duration>) {
+    if !Path::new(&get_log_file()).exists() {
+        eprintln!("No files in trash");
+        std::process::exit(1);
+    }
+
+    let cutoff = since.map(|since| Local::now() - chrono::Duration::seconds(since.as_secs() as i64));
+    let logs = read_logs(Filter::All);
+    let owner = owners(&logs);
+    let mut operations: Vec<(String, Vec<PathBuf>)> = vec![];
+
+    for (index, log) in logs.iter().enumerate().rev() {
+        if last.is_some_and(|last| operations.len() >= last) {
+            break;
+        }
+        if cutoff.is_some_and(|cutoff| log.moved_time < cutoff) {
+            break;
+        }
+        if log.operation != OpType::TRASH {
+            continue;
+        }
+        let files = remaining_items(&logs, &owner, index);
+        if !files.is_empty() {
+            operations.push((log.id(), files));
+        }
+    }
+
+    if operations.is_empty() {
+        eprintln!("No trash operation left to undo");
+        std::process::exit(1);
+    }
+
+    for (id, mut files) in operations {
+        if args.verbose || args.dry_run {
+            println!("Undoing operation {}", id);
+        }
+        recover_files(args, dir_path, &mut files, true, Some(&id));
+    }
+}
+
+/// The later trash operation which redid the restore at `index`, if any
+fn redone_by(logs: &[FileInfo], index: usize) -> Option<&FileInfo> {
+    let id = logs[index].id();
+    logs[index + 1..]
+        .iter()
+        .find(|log| log.operation == OpType::TRASH && log.reverts.as_ref() == Some(&id))
+}
+
+/// Trash again the files of a restore which are still where they were restored to.
+/// Without an ID, the latest restore which was not redone yet is taken, so that undo and redo
+/// walk back and forth through the history
+pub fn redo_operation(args: &Args, dir_path: &PathBuf, id: Option<&str>) {
+    if !Path::new(&get_log_file()).exists() {
+        eprintln!("No history to redo");
+        std::process::exit(1);
+    }
+
+    let logs = read_logs(Filter::All);
+    let index = match id {
+        Some(id) => find_operation(&logs, id),
+        None => (0..logs.len())
+            .rev()
+            .find(|&index| logs[index].operation == OpType::RESTORE && redone_by(&logs, index).is_none())
+            .ok_or_else(|| "No restore left to redo".to_string()),
+    };
+    let index = match index {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let log = &logs[index];
+    if log.operation != OpType::RESTORE {
+        eprintln!("Operation {} is a trash, only restores can be redone", log.id());
+        std::process::exit(1);
+    }
+    if let Some(redo) = redone_by(&logs, index) {
+        eprintln!("Operation {} was already redone by {}", log.id(), redo.id());
+        std::process::exit(1);
+    }
+
+    let key = crypt::history_key(args, std::slice::from_ref(log));
+    let mut files: Vec<PathBuf> = vec![];
+    for dst in &log.dst {
+        let path = crypt::reveal_path(dst, key.as_ref());
+        if crypt::is_sealed(dst) && path == crypt::HIDDEN_PATH {
+            eprintln!("Skipping a file whose path is encrypted");
+        } else if !utils::path_exists(Path::new(&path)) {
+            eprintln!("Skipping {}: No such file or directory", path);
+        } else {
+            files.push(PathBuf::from(path));
+        }
+    }
+    if files.is_empty() {
+        eprintln!("Nothing restored by operation {} is left to trash again", log.id());
+        std::process::exit(1);
+    }
+
+    move_files(args, dir_path, &files, Some(&log.id()));
+}
+
+/// List the versions of `path` which are in the trash, the oldest first, or restore one of them
+pub fn trashed_versions(args: &Args, dir_path: &PathBuf, path: &str, restore: Option<Option<usize>>) {
+    let original = utils::absolute_path(Path::new(path)).unwrap_or_else(|_| PathBuf::from(path));
+    let versions: Vec<TrashedItem> = trashed_originals()
+        .into_iter()
+        .filter(|item| item.original == original)
+        .collect();
+    if versions.is_empty() {
+        eprintln!("No version of {} is in the trash", original.display());
+        std::process::exit(1);
+    }
+
+    if let Some(number) = restore {
+        let number = number.unwrap_or(versions.len());
+        let Some(item) = number.checked_sub(1).and_then(|i| versions.get(i)) else {
+            eprintln!("{} has versions 1 to {} in the trash, not {}", original.display(), versions.len(), number);
+            std::process::exit(1);
+        };
+        recover_files(args, dir_path, &mut vec![item.dst.clone()], true, None);
+        return;
+    }
+
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_header(vec!["Version", "ID", "Trashed", "Path in trash", "Size"]);
+    for (i, item) in versions.iter().enumerate() {
+        let version = if i + 1 == versions.len() {
+            format!("{} (latest)", i + 1)
+        } else {
+            (i + 1).to_string()
+        };
+        let mut size = item.meta.as_ref().map_or(String::new(), |meta| HumanBytes(meta.size).to_string());
+        if item.compressed {
+            size = format!("{} (compressed)", size).trim_start().to_string();
+        }
+        table.add_row(vec![
+            version,
+            item.id.clone(),
+            item.trashed.format("%Y-%m-%d %H:%M:%S").to_string(),
+            item.dst.display().to_string(),
+            size,
+        ]);
+    }
+    println!("{}", table);
+}
+
+/// Restore what was under `dir` at the time `at` and was trashed since. The log is replayed up
+/// to `at` to know which paths were in the trash then, as those did not exist. Of every other
+/// path under `dir`, the first version trashed after `at` which is still in the trash comes back
+pub fn restore_at(args: &Args, dir_path: &PathBuf, dir: &str, at: DateTime<Local>) {
+    if !Path::new(&get_log_file()).exists() {
+        eprintln!("No files in trash");
+        std::process::exit(1);
+    }
+
+    // the directory may be gone, trashed along with everything in it
+    let dir = utils::absolute_path(Path::new(dir))
+        .unwrap_or_else(|_| std::env::current_dir().unwrap_or_default().join(dir));
+    let logs = read_logs(Filter::All);
+
+    let available: HashSet<(PathBuf, String)> = trashed_originals()
+        .into_iter()
+        .map(|item| (item.dst, item.id))
+        .collect();
+    let mut restored: HashSet<&String> = HashSet::new();
+    let mut items: Vec<(&String, PathBuf)> = trashed_since(&logs, &dir, at)
+        .into_iter()
+        .map(|(src, dst, id)| (src, (PathBuf::from(dst), id)))
+        .filter(|(_, item)| available.contains(item))
+        .filter(|(src, _)| restored.insert(src))
+        .map(|(src, (dst, _))| (src, dst))
+        .collect();
+    if items.is_empty() {
+        eprintln!(
+            "Nothing which was under {} at {} is in the trash",
+            dir.display(),
+            at.format("%Y-%m-%d %H:%M:%S")
+        );
+        std::process::exit(1);
+    }
+
+    // a directory comes back before what was trashed from inside it
+    items.sort_by(|a, b| Path::new(a.0).cmp(Path::new(b.0)));
+    let mut files: Vec<PathBuf> = items.into_iter().map(|(_, dst)| dst).collect();
+    recover_files(args, dir_path, &mut files, true, None);
+}
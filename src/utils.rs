@@ -1,41 +1,201 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
 use lscolors::LsColors;
 use std::{
+    collections::HashSet,
+    ffi::OsStr,
     fs,
-    io::{self, Error},
-    path::PathBuf,
+    io::{self, Error, ErrorKind, Read},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
 };
 use term_grid::{Grid, GridOptions};
 
+use crate::copy;
+use crate::progress::Progress;
 use crate::trm::{Args, get_default_dir};
 
 #[macro_export]
 macro_rules! get_file_name {
     ($path:expr) => {
-        $path.file_name().unwrap().to_str().unwrap().to_string()
+        $path.file_name().unwrap_or_default().to_string_lossy().into_owned()
     };
 }
 
+/// Like `Path::exists`, but does not follow a symlink in the final component,
+/// so dangling symlinks count as existing
+pub fn path_exists(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+/// Whether a file belongs to the user running trm
+pub fn owned_by_user(meta: &fs::Metadata) -> bool {
+    meta.uid() == unsafe { libc::getuid() }
+}
+
+/// Create `dir` if needed so that only the user can access it. An existing directory must be
+/// a real one owned by the user, not a symlink, as it may have been planted in a shared place
+/// like /tmp. Its permissions are tightened if they are too open
+pub fn create_private_dir(dir: &Path) -> Result<(), Error> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+
+    let meta = dir.symlink_metadata()?;
+    if !meta.is_dir() || !owned_by_user(&meta) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is not a directory owned by you", dir.display()),
+        ));
+    }
+    if meta.mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Make a path absolute by canonicalizing only its parent directory.
+/// The final component is kept as is, so a symlink stays a symlink instead of resolving to its target
+pub fn absolute_path(path: &Path) -> Result<PathBuf, Error> {
+    let file_name = match path.file_name() {
+        Some(name) => name,
+        // paths like `.` and `..` have no final component to keep
+        None => return path.canonicalize(),
+    };
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize()?,
+        _ => std::env::current_dir()?,
+    };
+
+    Ok(parent.join(file_name))
+}
+
+/// Name under which a cross-device copy is built before being renamed into place
+pub fn staging_path(new_location: &Path, pid: u32) -> PathBuf {
+    let file_name = new_location.file_name().unwrap_or_default().to_string_lossy();
+    new_location.with_file_name(format!(".{}.trm-staging-{}", file_name, pid))
+}
+
+/// Name a file to be overwritten is kept under until what replaces it is in place
+pub fn aside_path(path: &Path, pid: u32) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.trm-replaced-{}", file_name, pid))
+}
+
 pub fn move_content(original: &PathBuf, new_location: &PathBuf) -> Result<(), Error> {
-    match fs::rename(&original, &new_location) {
-        Ok(_) => (),
-        Err(_) => {
-            // do a copy and delete
-            if original.is_file() {
-                // if it is just a file, try normal copy and paste
-                fs::copy(&original, &new_location)?;
-                fs::remove_file(&original)?;
-                return Ok(());
-            }
+    if fs::rename(original, new_location).is_ok() {
+        return Ok(());
+    }
+
+    // do a copy and delete, keeping all the metadata so that an undo gives back the same file.
+    // The original is only removed once a complete, verified copy is in place
+    let stats = copy::tree_stats(original)?;
+    ensure_space(new_location.parent().unwrap_or(Path::new("/")), stats.disk_usage)?;
+
+    let name = get_file_name!(original);
+    let staging = staging_path(new_location, std::process::id());
+    let copied = copy_and_verify(original, &staging, &name, &stats)
+        .and_then(|_| fs::rename(&staging, new_location));
 
-            // perform copy and paste for a directory
-            dircpy::copy_dir(&original, &new_location)?;
-            fs::remove_dir_all(&original)?
+    if let Err(e) = copied {
+        if path_exists(&staging) {
+            let _ = remove_path(&staging);
         }
+        return Err(e);
     }
 
+    copy::remove_tree(
+        original,
+        &Progress::new(&format!("Removing {}", name), stats.bytes, stats.entries),
+    )
+}
+
+/// The first of `path_1`, `path_2`, ... which is not `taken`
+pub fn free_name(path: &Path, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    // names need not be valid UTF-8, so the number is appended to the raw name
+    let file_name = path.file_name().unwrap_or_default();
+    let with_number = |number: usize| {
+        let mut name = file_name.to_os_string();
+        name.push(format!("_{}", number));
+        path.with_file_name(name)
+    };
+
+    // find the version number in a 2 step process
+    // 1. Binary exponentiation to find upper limit
+    // 2. Binary search to find actual number
+    let mut search_start = 1;
+    let mut search_end = 1;
+    while taken(&with_number(search_end)) {
+        search_start = search_end;
+        search_end *= 2;
+    }
+
+    // Binary search
+    while search_start < search_end {
+        let middle = (search_start + search_end) / 2;
+        if taken(&with_number(middle)) {
+            search_start = middle + 1;
+        } else {
+            search_end = middle;
+        }
+    }
+
+    with_number(search_end)
+}
+
+/// Fail before copying anything if `needed` bytes do not fit in the filesystem of `dir`
+pub fn ensure_space(dir: &Path, needed: u64) -> Result<(), Error> {
+    let available = copy::available_space(dir)?;
+    if needed > available {
+        return Err(Error::new(
+            ErrorKind::StorageFull,
+            format!(
+                "Not enough space in {}: {} bytes needed, {} bytes available",
+                dir.display(),
+                needed,
+                available
+            ),
+        ));
+    }
     Ok(())
 }
 
+fn copy_and_verify(original: &Path, staging: &Path, name: &str, stats: &copy::TreeStats) -> Result<(), Error> {
+    let progress = Progress::new(&format!("Copying {}", name), stats.bytes, stats.entries);
+    copy::copy_tree(original, staging, &progress)?;
+    drop(progress);
+
+    let progress = Progress::new(&format!("Verifying {}", name), stats.bytes, stats.entries);
+    copy::verify_tree(original, staging, &progress)
+}
+
+/// Remove a file, symlink or a whole directory tree
+pub fn remove_path(path: &Path) -> Result<(), Error> {
+    if path.symlink_metadata()?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Remove the now empty parent directories of a path, stopping at `stop_at`
+pub fn remove_empty_parents(path: &Path, stop_at: &Path) {
+    let mut curr_parent = path.parent();
+    while let Some(parent) = curr_parent {
+        if parent == stop_at || !parent.starts_with(stop_at) {
+            break;
+        }
+        if fs::remove_dir(parent).is_err() {
+            break;
+        }
+        curr_parent = parent.parent();
+    }
+}
+
 pub fn display_files(files: &Vec<PathBuf>, only_filename: bool) {
     let lscolors = LsColors::from_env().unwrap_or_default();
     let stdout_width = terminal_size::terminal_size_of(io::stdout())
@@ -74,6 +234,104 @@ pub fn display_files(files: &Vec<PathBuf>, only_filename: bool) {
     println!("{grid}");
 }
 
+/// Parse a size like `500M`, `1.5G` or `10GiB`. Units are powers of 1024 and a plain number is in bytes
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split_at = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split_at);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size `{}`", size))?;
+    let multiplier: u64 = match unit.trim().to_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Invalid unit in size `{}`, expected one of K, M, G or T", size)),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse a moment like `2026-10-01 14:00`, `2026-10-01` or RFC 3339 in local time,
+/// or a time ago like `2h` with the semantics of `before` in history
+pub fn parse_time(time: &str) -> Result<DateTime<Local>, String> {
+    let time = time.trim();
+    if let Ok(parsed) = DateTime::parse_from_rfc3339(time) {
+        return Ok(parsed.with_timezone(&Local));
+    }
+
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(time, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(time, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        });
+    if let Some(naive) = naive {
+        return Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .ok_or_else(|| format!("`{}` does not exist in the local time zone", time));
+    }
+
+    match humantime::parse_duration(time) {
+        Ok(ago) => Ok(Local::now() - chrono::Duration::seconds(ago.as_secs() as i64)),
+        Err(_) => Err(format!("Invalid time `{}`, expected e.g. `2026-10-01 14:00` or `2h`", time)),
+    }
+}
+
+/// Whether the list of files is read from stdin, in which case answers to prompts cannot be
+pub fn list_from_stdin(args: &Args) -> bool {
+    args.stdin || args.from_file.as_deref() == Some("-")
+}
+
+/// Read the files given with --stdin or --from-file. Paths may hold any bytes but the separator,
+/// and empty entries are skipped
+pub fn read_file_list(args: &Args) -> Result<Vec<PathBuf>, Error> {
+    let mut contents: Vec<u8> = vec![];
+    match &args.from_file {
+        Some(file) if file != "-" => contents = fs::read(file)?,
+        _ => {
+            io::stdin().lock().read_to_end(&mut contents)?;
+        }
+    }
+
+    let separator = if args.null { b'\0' } else { b'\n' };
+    Ok(contents
+        .split(|byte| *byte == separator)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| PathBuf::from(OsStr::from_bytes(entry)))
+        .collect())
+}
+
+/// Drop repeated paths and paths inside another given directory, which go along with it.
+/// Lists such as the output of `find` name a directory as well as everything in it
+pub fn prune_nested(files: Vec<PathBuf>) -> Vec<PathBuf> {
+    let resolved: Vec<PathBuf> = files
+        .iter()
+        .map(|file| absolute_path(file).unwrap_or_else(|_| file.clone()))
+        .collect();
+    let dirs: HashSet<&PathBuf> = resolved
+        .iter()
+        .filter(|path| path.symlink_metadata().is_ok_and(|meta| meta.is_dir()))
+        .collect();
+
+    let mut seen: HashSet<&PathBuf> = HashSet::new();
+    files
+        .iter()
+        .zip(&resolved)
+        .filter(|(_, path)| seen.insert(path))
+        .filter(|(_, path)| !path.ancestors().skip(1).any(|parent| dirs.contains(&parent.to_path_buf())))
+        .map(|(file, _)| file.clone())
+        .collect()
+}
+
 pub fn setup_directory(args: &Args) -> Result<PathBuf, Error> {
     let dir: String;
     let mut var_dir: String = String::new();
@@ -95,6 +353,7 @@ pub fn setup_directory(args: &Args) -> Result<PathBuf, Error> {
 
     let dir_path = match PathBuf::from(&dir).canonicalize() {
         Ok(dir) => dir,
+        Err(_) if args.dry_run => PathBuf::from(&dir),
         Err(_) => {
             if let Err(e) = fs::create_dir_all(&dir) {
                 eprintln!("Failed to create directory {}: {}", dir, e);
@@ -113,3 +372,134 @@ pub fn setup_directory(args: &Args) -> Result<PathBuf, Error> {
 
     Ok(dir_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for a test, under the system temporary directory
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trm-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn non_utf8(name: &[u8]) -> PathBuf {
+        PathBuf::from(OsStr::from_bytes(name))
+    }
+
+    #[test]
+    fn file_name_of_non_utf8_path() {
+        let path = Path::new("/trash").join(non_utf8(b"bad\xff"));
+        assert_eq!(get_file_name!(path), "bad\u{fffd}");
+        assert_eq!(staging_path(&path, 7), PathBuf::from("/trash/.bad\u{fffd}.trm-staging-7"));
+    }
+
+    #[test]
+    fn copy_non_utf8_tree() {
+        let dir = test_dir("non-utf8");
+        let src = dir.join(non_utf8(b"bad\xff"));
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join(non_utf8(b"inner\xfe")), "contents").unwrap();
+
+        let stats = copy::tree_stats(&src).unwrap();
+        let dst = dir.join("copy");
+        copy_and_verify(&src, &dst, &get_file_name!(src), &stats).unwrap();
+        assert_eq!(fs::read(dst.join(non_utf8(b"inner\xfe"))).unwrap(), b"contents");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("10GiB"), Ok(10 << 30));
+        assert_eq!(parse_size(" 2 kb "), Ok(2 << 10));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+    }
+
+    #[test]
+    fn parse_invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("1.2.3M").is_err());
+    }
+
+    #[test]
+    fn prune_nested_files() {
+        let dir = test_dir("prune");
+        fs::create_dir_all(dir.join("d/sub")).unwrap();
+        fs::write(dir.join("d/sub/x"), "").unwrap();
+        fs::write(dir.join("dx"), "").unwrap();
+
+        let files = vec![
+            dir.join("d/sub/x"),
+            dir.join("dx"),
+            dir.join("d"),
+            dir.join("d/sub"),
+            dir.join("dx"),
+            dir.join("missing"),
+        ];
+        // `dx` only shares a prefix with `d`, and duplicates are kept once in their first place
+        assert_eq!(prune_nested(files), vec![dir.join("dx"), dir.join("d"), dir.join("missing")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn free_names() {
+        let path = Path::new("/trash/notes.txt");
+        assert_eq!(free_name(path, |_| false), PathBuf::from("/trash/notes.txt_1"));
+
+        for count in [1, 2, 5, 8, 100] {
+            let taken = |candidate: &Path| {
+                (1..=count).any(|number| candidate == Path::new(&format!("/trash/notes.txt_{}", number)))
+            };
+            assert_eq!(free_name(path, taken), PathBuf::from(format!("/trash/notes.txt_{}", count + 1)));
+        }
+    }
+
+    #[test]
+    fn free_name_keeps_non_utf8_bytes() {
+        let path = Path::new("/trash").join(non_utf8(b"bad\xff"));
+        let taken = |candidate: &Path| candidate == Path::new("/trash").join(non_utf8(b"bad\xff_1"));
+        assert_eq!(free_name(&path, taken), Path::new("/trash").join(non_utf8(b"bad\xff_2")));
+    }
+
+    fn local(time: &str) -> DateTime<Local> {
+        let naive = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap();
+        Local.from_local_datetime(&naive).earliest().unwrap()
+    }
+
+    #[test]
+    fn parse_times() {
+        assert_eq!(parse_time("2026-10-01 14:00"), Ok(local("2026-10-01 14:00:00")));
+        assert_eq!(parse_time("2026-10-01 14:00:30"), Ok(local("2026-10-01 14:00:30")));
+        assert_eq!(parse_time("2026-10-01T14:00"), Ok(local("2026-10-01 14:00:00")));
+        assert_eq!(parse_time(" 2026-10-01 "), Ok(local("2026-10-01 00:00:00")));
+        assert_eq!(
+            parse_time("2026-10-01T12:00:00Z"),
+            Ok(DateTime::parse_from_rfc3339("2026-10-01T12:00:00+00:00").unwrap().with_timezone(&Local))
+        );
+    }
+
+    #[test]
+    fn parse_time_ago() {
+        let before = Local::now();
+        let parsed = parse_time("2h").unwrap();
+        let expected = before - chrono::Duration::hours(2);
+        assert!(parsed >= expected && parsed - expected < chrono::Duration::seconds(5));
+    }
+
+    #[test]
+    fn parse_invalid_times() {
+        assert!(parse_time("").is_err());
+        assert!(parse_time("yesterday-ish").is_err());
+        assert!(parse_time("2026-13-01").is_err());
+        assert!(parse_time("2026-10-01 25:00").is_err());
+    }
+}